@@ -0,0 +1,115 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use serde::Serialize;
+use warp::http::StatusCode;
+
+use crate::file_system::FileSystemError;
+
+/// The body of every failed API request:
+///
+/// ```json
+/// {"code": "unknown_id", "message": "No item with id 42", "source": "domain", "errors": []}
+/// ```
+///
+/// `code` is stable and meant for programs, `message` is meant for humans and may change.
+/// `source` tells errors of the file system (`domain`) apart from requests warp rejected before
+/// reaching a handler (`request`), e.g. unknown routes or malformed bodies. `errors` lists the
+/// individual failures of a batch operation and is left out otherwise.
+#[derive(Serialize, Debug)]
+pub struct ApiError {
+    pub code: &'static str,
+    pub message: String,
+    pub source: ErrorSource,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ApiError>,
+
+    #[serde(skip)]
+    pub status: StatusCode,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorSource {
+    Domain,
+    Request,
+}
+
+impl ApiError {
+    fn domain(status: StatusCode, code: &'static str, message: String) -> Self {
+        ApiError {
+            code,
+            message,
+            source: ErrorSource::Domain,
+            errors: Vec::new(),
+            status,
+        }
+    }
+
+    pub fn request(status: StatusCode, code: &'static str, message: String) -> Self {
+        ApiError {
+            code,
+            message,
+            source: ErrorSource::Request,
+            errors: Vec::new(),
+            status,
+        }
+    }
+}
+
+impl From<FileSystemError> for ApiError {
+    fn from(e: FileSystemError) -> Self {
+        match e {
+            FileSystemError::UnknownId(id) => ApiError::domain(StatusCode::NOT_FOUND, "unknown_id",
+                                                               format!("No item or job with id {}", id)),
+            FileSystemError::UnknownPath(path) => ApiError::domain(StatusCode::NOT_FOUND, "unknown_path",
+                                                                   format!("No item at {:?}", path)),
+            FileSystemError::UnknownInbox(name) => ApiError::domain(StatusCode::NOT_FOUND, "unknown_inbox",
+                                                                    format!("No inbox named '{}'", name)),
+            FileSystemError::UnknownDestination(id) => ApiError::domain(StatusCode::NOT_FOUND, "unknown_destination",
+                                                                        format!("No destination with id '{}'", id)),
+            FileSystemError::FileNotFound(path) => ApiError::domain(StatusCode::NOT_FOUND, "file_not_found",
+                                                                    format!("The file {:?} doesn't exist", path)),
+            FileSystemError::InvalidParameters(message) => ApiError::domain(StatusCode::BAD_REQUEST, "invalid_parameters", message),
+            FileSystemError::DestinationExists(message) => ApiError::domain(StatusCode::CONFLICT, "destination_exists", message),
+            FileSystemError::Conflict(message) => ApiError::domain(StatusCode::CONFLICT, "conflict", message),
+            FileSystemError::ChecksumMismatch(path) => ApiError::domain(StatusCode::INTERNAL_SERVER_ERROR, "checksum_mismatch",
+                                                                        format!("The copy of {:?} differs from it; the source was kept", path)),
+            FileSystemError::StorageFull(message) => ApiError::domain(StatusCode::INSUFFICIENT_STORAGE, "storage_full", message),
+            FileSystemError::NoParentDirectory(path) => ApiError::domain(StatusCode::INTERNAL_SERVER_ERROR, "no_parent_directory",
+                                                                         format!("{:?} has no parent directory", path)),
+            FileSystemError::IOError(message) => ApiError::domain(StatusCode::INTERNAL_SERVER_ERROR, "io_error", message),
+            FileSystemError::ImageError(message) => ApiError::domain(StatusCode::INTERNAL_SERVER_ERROR, "image_error", message),
+            FileSystemError::Other(message) => ApiError::domain(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message),
+            FileSystemError::MultipleErrors(errors) => {
+                let errors = errors.into_iter().map(ApiError::from).collect::<Vec<ApiError>>();
+                // a batch only reports a specific status if all of its failures agree on it
+                let status = match errors.first() {
+                    Some(first) if errors.iter().all(|e| e.status == first.status) => first.status,
+                    _ if errors.iter().all(|e| e.status.is_client_error()) => StatusCode::BAD_REQUEST,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                ApiError {
+                    code: "multiple_errors",
+                    message: format!("{} of the requested operations failed", errors.len()),
+                    source: ErrorSource::Domain,
+                    errors,
+                    status,
+                }
+            }
+        }
+    }
+}
@@ -16,9 +16,19 @@
 
 use serde::{Deserialize, Serialize};
 use warp::http::StatusCode;
-use warp::reply::{with_header, with_status};
+use warp::Reply;
+use warp::reply::{with_header, with_status, WithHeader, WithStatus};
 
-use crate::file_system::FileSystem;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use log::warn;
+
+use crate::api_error::ApiError;
+use crate::auth::{Auth, Forbidden, Principal, Unauthorized};
+use crate::file_system::{FileSystem, FileSystemError};
+use crate::file_system::destinations::DestinationSettings;
+use crate::file_system::model::{ItemResult, Job};
 
 const APPL_JSON: &str = "application/json";
 const TEXT_PLN: &str = "text/plain";
@@ -31,18 +41,106 @@ pub struct DiscardMediaItems {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfirmMediaItems {
-    destination: u64,
+    destination: String,
+    ids: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrashedMediaItems {
+    ids: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UndoMediaItems {
     ids: Vec<u64>,
 }
 
-pub async fn handle_list_items(fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
-    match fs.list().await {
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PreviewQuery {
+    width: Option<u32>,
+    height: Option<u32>,
+    /// JPEG quality between 1 and 100
+    quality: Option<u8>,
+}
+
+const DEFAULT_JOURNAL_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct Login {
+    name: String,
+    password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobQuery {
+    limit: Option<usize>,
+}
+
+const DEFAULT_JOB_LIMIT: usize = 20;
+
+#[derive(Serialize, Debug)]
+pub struct JobReport {
+    #[serde(flatten)]
+    job: Job,
+    results: Vec<ItemReport>,
+}
+
+/// One entry of the response of a batch operation; batches answer with 200 even if some of their items failed
+#[derive(Serialize, Debug)]
+pub struct ItemReport {
+    #[serde(flatten)]
+    result: ItemResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GroupQuery {
+    /// How many of the 64 bits of the perceptual hashes may differ
+    max_distance: Option<u32>,
+    /// How far apart in time neighbouring frames of a group may be
+    max_gap_seconds: Option<i64>,
+    /// Only groups items of this inbox
+    inbox: Option<String>,
+}
+
+const DEFAULT_GROUP_MAX_DISTANCE: u32 = 10;
+const DEFAULT_GROUP_MAX_GAP_SECONDS: i64 = 3;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListQuery {
+    /// Only lists items in this folder of the inbox or below it
+    subfolder: Option<String>,
+    /// Only lists items of this inbox
+    inbox: Option<String>,
+}
+
+/// Restricts a request to the items of one inbox
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InboxQuery {
+    inbox: Option<String>,
+}
+
+pub async fn handle_list_items(fs: FileSystem, query: ListQuery) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.list(query.subfolder, query.inbox).await {
         Ok(items) => {
             Ok(reply(json(&items), APPL_JSON, StatusCode::OK))
         }
-        Err(e) => {
-            Ok(reply(json(&e), APPL_JSON, StatusCode::INTERNAL_SERVER_ERROR))
-        }
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_group_items(fs: FileSystem, query: GroupQuery) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let max_distance = query.max_distance.unwrap_or(DEFAULT_GROUP_MAX_DISTANCE);
+    let max_gap = chrono::Duration::seconds(query.max_gap_seconds.unwrap_or(DEFAULT_GROUP_MAX_GAP_SECONDS));
+    match fs.group_similar(max_distance, max_gap, query.inbox).await {
+        Ok(groups) => Ok(reply(json(&groups), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
     }
 }
 
@@ -51,37 +149,284 @@ pub async fn handle_load_item(image_id: u64, fs: FileSystem) -> Result<impl warp
         Ok(data) => {
             Ok(reply(data, IMAGE_JPG, StatusCode::OK))
         }
-        Err(e) => Ok(reply(json(&e), APPL_JSON, StatusCode::INTERNAL_SERVER_ERROR))
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_load_original(image_id: u64, fs: FileSystem) -> Result<warp::reply::Response, std::convert::Infallible> {
+    match fs.read_original(image_id).await {
+        Ok((item, file)) => {
+            let body = warp::http::Response::new(warp::hyper::Body::wrap_stream(tokio_util::io::ReaderStream::new(file)));
+            let disposition = format!("inline; filename=\"{}\"", item.name.replace('"', ""));
+            let response = with_header(reply(body, item.mime.as_str(), StatusCode::OK), warp::http::header::CONTENT_DISPOSITION, disposition);
+            Ok(response.into_response())
+        }
+        Err(e) => Ok(error_reply(e).into_response())
+    }
+}
+
+pub async fn handle_load_preview(image_id: u64, fs: FileSystem, query: PreviewQuery) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.read_preview(image_id, query.width, query.height, query.quality).await {
+        Ok(data) => Ok(reply(data, IMAGE_JPG, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
     }
 }
 
 pub async fn handle_discard_items(fs: FileSystem, body: DiscardMediaItems) -> Result<impl warp::Reply, std::convert::Infallible> {
     match fs.discard(body.ids).await {
-        Ok(_) => Ok(reply("".to_string().into_bytes(), TEXT_PLN, StatusCode::OK)),
-        Err(e) => Ok(reply(json(&e), APPL_JSON, StatusCode::INTERNAL_SERVER_ERROR))
+        Ok(results) => Ok(reply(json(&item_reports(results)), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
     }
 }
 
-pub async fn handle_discard_all(fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
-    match fs.discard_all().await {
-        Ok(_) => Ok(reply("".to_string().into_bytes(), TEXT_PLN, StatusCode::OK)),
-        Err(e) => Ok(reply(json(&e), APPL_JSON, StatusCode::INTERNAL_SERVER_ERROR))
+pub async fn handle_discard_all(fs: FileSystem, query: InboxQuery) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.discard_all(query.inbox).await {
+        Ok(results) => Ok(reply(json(&item_reports(results)), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_discard_filed(fs: FileSystem, query: InboxQuery) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.discard_filed(query.inbox).await {
+        Ok(results) => Ok(reply(json(&item_reports(results)), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
     }
 }
 
 pub async fn handle_confirm_items(fs: FileSystem, body: ConfirmMediaItems) -> Result<impl warp::Reply, std::convert::Infallible> {
     match fs.confirm(&body.destination, body.ids).await {
+        Ok(results) => Ok(reply(json(&item_reports(results)), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_submit_confirm(fs: FileSystem, body: ConfirmMediaItems) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.submit_confirm(body.destination, body.ids).await {
+        Ok(job) => Ok(reply(json(&job_report(job)), APPL_JSON, StatusCode::ACCEPTED)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_submit_discard(fs: FileSystem, body: DiscardMediaItems) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let job = fs.submit_discard(body.ids).await;
+    Ok(reply(json(&job_report(job)), APPL_JSON, StatusCode::ACCEPTED))
+}
+
+pub async fn handle_list_jobs(fs: FileSystem, query: JobQuery) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let limit = query.limit.unwrap_or(DEFAULT_JOB_LIMIT);
+    let jobs = fs.list_jobs(limit).await.into_iter().map(job_report).collect::<Vec<JobReport>>();
+    Ok(reply(json(&jobs), APPL_JSON, StatusCode::OK))
+}
+
+pub async fn handle_get_job(job_id: u64, fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.job(job_id).await {
+        Ok(job) => Ok(reply(json(&job_report(job)), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_cancel_job(job_id: u64, fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.cancel_job(job_id).await {
+        Ok(job) => Ok(reply(json(&job_report(job)), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_list_trash(fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    Ok(reply(json(&fs.list_trash().await), APPL_JSON, StatusCode::OK))
+}
+
+pub async fn handle_restore_items(fs: FileSystem, body: TrashedMediaItems) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.restore(body.ids).await {
         Ok(_) => Ok(reply("".to_string().into_bytes(), TEXT_PLN, StatusCode::OK)),
-        Err(e) => Ok(reply(json(&e), APPL_JSON, StatusCode::INTERNAL_SERVER_ERROR))
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_purge_items(fs: FileSystem, body: TrashedMediaItems) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.purge(body.ids).await {
+        Ok(_) => Ok(reply("".to_string().into_bytes(), TEXT_PLN, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_purge_all(fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.purge_all().await {
+        Ok(_) => Ok(reply("".to_string().into_bytes(), TEXT_PLN, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_list_journal(fs: FileSystem, query: JournalQuery) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let limit = query.limit.unwrap_or(DEFAULT_JOURNAL_LIMIT);
+    Ok(reply(json(&fs.list_journal(limit).await), APPL_JSON, StatusCode::OK))
+}
+
+pub async fn handle_undo_items(fs: FileSystem, body: UndoMediaItems) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.undo(body.ids).await {
+        Ok(_) => Ok(reply("".to_string().into_bytes(), TEXT_PLN, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+/// Streams inbox events as server-sent events, named by their type with the event as JSON data.
+/// Clients which fall behind receive a `resync` event and should reload the item list.
+pub async fn handle_events(fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let events = BroadcastStream::new(fs.subscribe().await).map(|event| {
+        match event {
+            Ok(event) => warp::sse::Event::default().event(event.name()).json_data(&event),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                warn!("An event subscriber missed {} events", missed);
+                Ok(warp::sse::Event::default().event("resync").data(missed.to_string()))
+            }
+        }
+    });
+    Ok(with_header(warp::sse::reply(warp::sse::keep_alive().stream(events)), warp::http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+}
+
+pub async fn handle_list_destinations(fs: FileSystem, query: InboxQuery) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.list_confirm_destinations(query.inbox).await {
+        Ok(destinations) => Ok(reply(json(&destinations), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_list_destination_settings(fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    Ok(reply(json(&fs.list_destination_settings().await), APPL_JSON, StatusCode::OK))
+}
+
+pub async fn handle_create_destination(fs: FileSystem, body: DestinationSettings) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.create_destination(body).await {
+        Ok(destination) => Ok(reply(json(&destination), APPL_JSON, StatusCode::CREATED)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_update_destination(id: String, fs: FileSystem, body: DestinationSettings) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.update_destination(id, body).await {
+        Ok(destination) => Ok(reply(json(&destination), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_disable_destination(id: String, fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.set_destination_disabled(id, true).await {
+        Ok(destination) => Ok(reply(json(&destination), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_enable_destination(id: String, fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.set_destination_disabled(id, false).await {
+        Ok(destination) => Ok(reply(json(&destination), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_delete_destination(id: String, fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.delete_destination(id).await {
+        Ok(_) => Ok(reply("".to_string().into_bytes(), TEXT_PLN, StatusCode::NO_CONTENT)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_list_rules(fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    Ok(reply(json(&fs.list_rules().await), APPL_JSON, StatusCode::OK))
+}
+
+pub async fn handle_simulate_rules(fs: FileSystem, query: InboxQuery) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match fs.simulate_rules(query.inbox).await {
+        Ok(simulated) => Ok(reply(json(&simulated), APPL_JSON, StatusCode::OK)),
+        Err(e) => Ok(error_reply(e))
+    }
+}
+
+pub async fn handle_list_inboxes(fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
+    Ok(reply(json(&fs.list_inboxes().await), APPL_JSON, StatusCode::OK))
+}
+
+pub async fn handle_login(auth: Auth, body: Login) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match auth.login(&body.name, body.password).await {
+        Some((token, principal)) => {
+            let cookie = auth.session_cookie(&token);
+            // the token only travels in the HttpOnly cookie; API clients use tokens from the user config
+            Ok(with_header(reply(json(&principal), APPL_JSON, StatusCode::OK), warp::http::header::SET_COOKIE, cookie).into_response())
+        }
+        None => {
+            let error = ApiError::request(StatusCode::UNAUTHORIZED, "invalid_credentials", "Unknown user or wrong password".to_string());
+            Ok(reply(json(&error), APPL_JSON, error.status).into_response())
+        }
+    }
+}
+
+pub async fn handle_logout(auth: Auth, token: Option<String>) -> Result<impl warp::Reply, std::convert::Infallible> {
+    if let Some(token) = token {
+        auth.logout(&token).await;
+    }
+    let cookie = auth.expired_session_cookie();
+    Ok(with_header(reply(warp::reply(), TEXT_PLN, StatusCode::NO_CONTENT), warp::http::header::SET_COOKIE, cookie))
+}
+
+pub async fn handle_whoami(principal: Principal) -> Result<impl warp::Reply, std::convert::Infallible> {
+    Ok(reply(json(&principal), APPL_JSON, StatusCode::OK))
+}
+
+/// Turns everything warp rejected below `/api/v1` into an `ApiError`, so clients get the same schema as for domain errors.
+pub async fn handle_rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, std::convert::Infallible> {
+    // authentication runs ahead of the routes, so its rejections come along with theirs
+    let error = if rejection.find::<Unauthorized>().is_some() {
+        ApiError::request(StatusCode::UNAUTHORIZED, "unauthorized", "Log in or send an API token".to_string())
+    } else if let Some(Forbidden(role)) = rejection.find::<Forbidden>() {
+        ApiError::request(StatusCode::FORBIDDEN, "forbidden", format!("This requires the {:?} role", role))
+    } else if rejection.is_not_found() {
+        ApiError::request(StatusCode::NOT_FOUND, "not_found", "No such endpoint".to_string())
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        ApiError::request(StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        ApiError::request(StatusCode::BAD_REQUEST, "invalid_query", e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::PayloadTooLarge>() {
+        ApiError::request(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::LengthRequired>() {
+        ApiError::request(StatusCode::LENGTH_REQUIRED, "length_required", e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+        ApiError::request(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MethodNotAllowed>() {
+        ApiError::request(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", e.to_string())
+    } else {
+        warn!("Unhandled rejection: {:?}", rejection);
+        ApiError::request(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", format!("{:?}", rejection))
+    };
+    Ok(reply(json(&error), APPL_JSON, error.status))
+}
+
+fn item_reports(results: Vec<ItemResult>) -> Vec<ItemReport> {
+    results.into_iter()
+        .map(|mut result| {
+            let error = result.error.take().map(ApiError::from);
+            ItemReport {
+                result,
+                error,
+            }
+        })
+        .collect::<Vec<ItemReport>>()
+}
+
+fn job_report(mut job: Job) -> JobReport {
+    let results = item_reports(std::mem::take(&mut job.results));
+    JobReport {
+        job,
+        results,
     }
 }
 
-pub async fn handle_list_destinations(fs: FileSystem) -> Result<impl warp::Reply, std::convert::Infallible> {
-    Ok(reply(json(&fs.list_confirm_destinations().await), APPL_JSON, StatusCode::OK))
+fn error_reply(e: FileSystemError) -> WithStatus<WithHeader<Vec<u8>>> {
+    let error = ApiError::from(e);
+    reply(json(&error), APPL_JSON, error.status)
 }
 
-fn reply(response: Vec<u8>, ctype: &str, rcode: StatusCode) -> impl warp::Reply {
-    with_status(with_header(with_header(response, warp::http::header::CONTENT_TYPE, ctype), warp::http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"), rcode)
+/// There is no `Access-Control-Allow-Origin`, so browsers only let the frontend served alongside the API read its responses.
+fn reply<T: warp::Reply>(response: T, ctype: &str, rcode: StatusCode) -> WithStatus<WithHeader<T>> {
+    with_status(with_header(response, warp::http::header::CONTENT_TYPE, ctype), rcode)
 }
 
 fn json<V: Serialize>(val: &V) -> Vec<u8> {
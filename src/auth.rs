@@ -0,0 +1,251 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use warp::Filter;
+use log::{info, warn};
+
+pub const SESSION_COOKIE: &str = "filebase_session";
+
+/// Sessions end this long after the login
+const SESSION_HOURS: i64 = 24 * 7;
+
+/// Admins may do everything viewers may; the order of the variants matters.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Browse the inbox, thumbnails, trash, journal and jobs
+    Viewer,
+    /// Confirm, discard, restore, purge and undo as well
+    Admin,
+}
+
+/// One entry of the user config:
+///
+/// ```json
+/// {"name": "alice", "role": "admin", "password_hash": "$argon2id$...", "api_tokens": ["<sha256 of the token>"]}
+/// ```
+///
+/// Password hashes are printed by `filebase hash-password`; API tokens are stored as the hex
+/// encoded SHA-256 of the token, e.g. from `sha256sum`.
+#[derive(Deserialize, Debug)]
+struct User {
+    name: String,
+    role: Role,
+    #[serde(default)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    api_tokens: Vec<String>,
+}
+
+/// Who sent a request
+#[derive(Serialize, Clone, Debug)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+}
+
+struct Session {
+    principal: Principal,
+    expires_on: chrono::DateTime<chrono::Utc>,
+}
+
+/// The request carries no valid session or API token.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// The request's user lacks the role the endpoint requires.
+#[derive(Debug)]
+pub struct Forbidden(pub Role);
+
+impl warp::reject::Reject for Forbidden {}
+
+/// Authenticates requests by session cookie or `Authorization: Bearer` token. With authentication
+/// disabled every request is let through as admin, as before users existed.
+#[derive(Clone)]
+pub struct Auth(Arc<AuthInternal>);
+
+struct AuthInternal {
+    users: Option<Vec<User>>,
+    sessions: RwLock<HashMap<String, Session>>,
+    /// Served over TLS, so browsers may only send the session cookie over it as well
+    secure_cookies: bool,
+}
+
+impl Auth {
+    /// Reads the users from `path`, which has to name at least one user unless `disabled` is set.
+    /// With `secure_cookies`, session cookies are marked to only be sent over HTTPS.
+    pub fn from_file<P: AsRef<Path>>(path: P, disabled: bool, secure_cookies: bool) -> Result<Self, String> {
+        let path = path.as_ref();
+        let users = if disabled {
+            warn!("Authentication is disabled; Everybody who can reach the server is an admin!");
+            None
+        } else {
+            let file = File::open(path)
+                .map_err(|e| format!("Failed to open the users in {:?}: {}; add them or set 'disable_auth' to run without authentication", path, e))?;
+            let users: Vec<User> = serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| format!("Failed to parse the users in {:?}: {}", path, e))?;
+            for user in users.iter() {
+                info!("Adding {:?} user '{}'", user.role, user.name);
+                if let Some(hash) = &user.password_hash {
+                    PasswordHash::new(hash)
+                        .map_err(|e| format!("The password hash of user '{}' is invalid: {}", user.name, e))?;
+                }
+            }
+            if users.is_empty() {
+                return Err(format!("There are no users in {:?}; add some or set 'disable_auth' to run without authentication", path));
+            }
+            Some(users)
+        };
+
+        Ok(Auth(Arc::new(AuthInternal {
+            users,
+            sessions: RwLock::new(HashMap::new()),
+            secure_cookies,
+        })))
+    }
+
+    /// The `Set-Cookie` value handing out the session `token`.
+    pub fn session_cookie(&self, token: &str) -> String {
+        self.cookie(&format!("{}={}", SESSION_COOKIE, token))
+    }
+
+    /// The `Set-Cookie` value making the browser drop the session cookie.
+    pub fn expired_session_cookie(&self) -> String {
+        self.cookie(&format!("{}=; Max-Age=0", SESSION_COOKIE))
+    }
+
+    fn cookie(&self, value: &str) -> String {
+        let secure = if self.0.secure_cookies { "; Secure" } else { "" };
+        format!("{}; Path=/; HttpOnly; SameSite=Strict{}", value, secure)
+    }
+
+    /// Checks the password and returns a new session token.
+    pub async fn login(&self, name: &str, password: String) -> Option<(String, Principal)> {
+        let user = self.0.users.as_ref()?.iter().find(|user| user.name == name)?;
+        let hash = user.password_hash.clone()?;
+        // hashing is slow on purpose, so it must not block the executor
+        let valid = tokio::task::spawn_blocking(move || verify_password(&password, &hash)).await.unwrap_or(false);
+        if !valid {
+            warn!("Failed login of user '{}'", name);
+            return None;
+        }
+
+        let principal = Principal {
+            name: user.name.clone(),
+            role: user.role,
+        };
+        let token = generate_token();
+        let mut sessions = self.0.sessions.write().await;
+        let now = chrono::Utc::now();
+        sessions.retain(|_, session| session.expires_on > now);
+        sessions.insert(token.clone(), Session {
+            principal: principal.clone(),
+            expires_on: now + chrono::Duration::hours(SESSION_HOURS),
+        });
+        info!("User '{}' logged in", name);
+        Some((token, principal))
+    }
+
+    pub async fn logout(&self, token: &str) {
+        self.0.sessions.write().await.remove(token);
+    }
+
+    async fn authenticate(&self, token: Option<String>) -> Option<Principal> {
+        let users = match &self.0.users {
+            Some(users) => users,
+            None => return Some(Principal {
+                name: "anonymous".to_string(),
+                role: Role::Admin,
+            })
+        };
+        let token = token?;
+
+        if let Some(session) = self.0.sessions.read().await.get(&token) {
+            if session.expires_on > chrono::Utc::now() {
+                return Some(session.principal.clone());
+            }
+        }
+
+        let token_hash = format!("{:x}", Sha256::digest(token.as_bytes()));
+        users.iter()
+            .find(|user| user.api_tokens.iter().any(|t| t.eq_ignore_ascii_case(&token_hash)))
+            .map(|user| Principal {
+                name: user.name.clone(),
+                role: user.role,
+            })
+    }
+}
+
+/// Hashes the password for the user config.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash the password!")
+        .to_string()
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false
+    }
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+}
+
+/// The session cookie or, for API clients, the bearer token of the request.
+pub fn token() -> impl Filter<Extract=(Option<String>,), Error=warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .map(|header: Option<String>, cookie: Option<String>| {
+            header.and_then(|h| h.strip_prefix("Bearer ").map(|t| t.trim().to_string()))
+                .or(cookie)
+        })
+}
+
+/// Rejects requests of users which don't have at least `role` and extracts the user otherwise.
+pub fn with_role(auth: Auth, role: Role) -> impl Filter<Extract=(Principal,), Error=warp::Rejection> + Clone {
+    token()
+        .and_then(move |token: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                match auth.authenticate(token).await {
+                    Some(principal) if principal.role >= role => Ok(principal),
+                    Some(_) => Err(warp::reject::custom(Forbidden(role))),
+                    None => Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+}
@@ -0,0 +1,349 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use serde::Deserialize;
+
+const DEFAULT_CONFIG: &str = "filebase.json";
+
+/// Watches an inbox folder and files the photos arriving there into a library.
+#[derive(Parser, Debug)]
+#[command(name = "filebase", version)]
+pub struct Cli {
+    /// Inbox folder to watch; overrides `inbox` and `inboxes` of the config file
+    pub inbox: Option<PathBuf>,
+
+    /// Config file [default: filebase.json, if it exists]
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, may be given multiple times; overrides `listen` of the config file
+    #[arg(short, long)]
+    pub listen: Vec<SocketAddr>,
+
+    /// One of off, error, warn, info, debug or trace; overrides `log_level` of the config file
+    #[arg(long)]
+    pub log_level: Option<LevelFilter>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Reads a password from stdin and prints its hash for `password_hash` in the user config
+    HashPassword,
+}
+
+/// The config file, e.g.
+///
+/// ```json
+/// {"inbox": "/srv/inbox", "listen": ["0.0.0.0:5555"], "tls": {"cert": "cert.pem", "key": "key.pem"}}
+/// ```
+///
+/// or, with several inboxes,
+///
+/// ```json
+/// {"inboxes": [{"name": "phone", "path": "/srv/phone", "destinations": ["deep"]}, {"name": "camera", "path": "/srv/camera"}]}
+/// ```
+///
+/// Everything but `inbox` or `inboxes` may be left out. Relative paths are resolved against the
+/// folder of the config file; without a config file, against the working directory.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// A single inbox named after its folder; becomes the only entry of `inboxes` once validated
+    pub inbox: Option<PathBuf>,
+    #[serde(default)]
+    pub inboxes: Vec<InboxConfig>,
+    /// Keeps the items, trash, journal and library index of all inboxes; defaults to `.filebase` inside the first inbox
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+    /// Defaults to the `SERVER_IP` environment variable, which set the address before there was a
+    /// config file, or to 127.0.0.1:5555
+    #[serde(default)]
+    pub listen: Vec<SocketAddr>,
+    #[serde(default = "default_destinations")]
+    pub destinations: PathBuf,
+    #[serde(default = "default_users")]
+    pub users: PathBuf,
+    /// Lets everybody who can reach the server act as admin instead of requiring the users to log in
+    #[serde(default)]
+    pub disable_auth: bool,
+    /// Without a rules file every item is confirmed by hand
+    #[serde(default = "default_rules")]
+    pub rules: PathBuf,
+    /// The built frontend which is served next to the API
+    #[serde(default = "default_frontend")]
+    pub frontend: PathBuf,
+    /// The thumbnail cache of `inbox`; set it per inbox when using `inboxes`
+    #[serde(default)]
+    pub thumbnail_dir: Option<PathBuf>,
+    /// Edge length of the square thumbnails fit into, in pixels
+    #[serde(default = "default_thumbnail_size")]
+    pub thumbnail_size: u32,
+    #[serde(default = "default_preview_max_size")]
+    pub preview_max_size: u32,
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: i64,
+    #[serde(default = "default_library_index_interval_minutes")]
+    pub library_index_interval_minutes: u64,
+    /// Serves HTTPS instead of HTTP if given
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default = "default_log_level")]
+    pub log_level: LevelFilter,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InboxConfig {
+    pub name: String,
+    pub path: PathBuf,
+    /// Defaults to `.thumbnails` inside the inbox
+    #[serde(default)]
+    pub thumbnail_dir: Option<PathBuf>,
+    /// The ids of the destinations offered for its items; all of them if left out
+    #[serde(default)]
+    pub destinations: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Unreadable(PathBuf, String),
+    Unparsable(PathBuf, String),
+    Invalid(String),
+}
+
+fn default_listen() -> Result<Vec<SocketAddr>, ConfigError> {
+    match std::env::var("SERVER_IP") {
+        Ok(address) => address.parse()
+            .map(|address| vec![address])
+            .map_err(|e| ConfigError::Invalid(format!("SERVER_IP '{}' is no address: {}", address, e))),
+        Err(_) => Ok(vec![SocketAddr::from(([127, 0, 0, 1], 5555))])
+    }
+}
+
+fn default_destinations() -> PathBuf {
+    PathBuf::from("destination_config.json")
+}
+
+fn default_users() -> PathBuf {
+    PathBuf::from("user_config.json")
+}
+
+fn default_rules() -> PathBuf {
+    PathBuf::from("rule_config.json")
+}
+
+fn default_frontend() -> PathBuf {
+    PathBuf::from("frontend/public")
+}
+
+fn default_thumbnail_size() -> u32 {
+    512
+}
+
+fn default_preview_max_size() -> u32 {
+    2048
+}
+
+fn default_trash_retention_days() -> i64 {
+    30
+}
+
+fn default_library_index_interval_minutes() -> u64 {
+    60
+}
+
+fn default_log_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+impl Config {
+    /// Reads the config file, applies the command line on top and checks the result.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let (mut config, base_dir) = match &cli.config {
+            Some(path) => (Self::from_file(path)?, path.parent().map(Path::to_path_buf).unwrap_or_default()),
+            None if Path::new(DEFAULT_CONFIG).is_file() => (Self::from_file(Path::new(DEFAULT_CONFIG))?, PathBuf::new()),
+            None => (serde_json::from_str::<Config>("{}").expect("The defaults don't parse!"), PathBuf::new()),
+        };
+        config.resolve_against(&base_dir);
+
+        // paths on the command line are relative to the working directory as usual
+        if let Some(inbox) = &cli.inbox {
+            config.inbox = Some(inbox.clone());
+            config.inboxes.clear();
+        }
+        if !cli.listen.is_empty() {
+            config.listen = cli.listen.clone();
+        } else if config.listen.is_empty() {
+            config.listen = default_listen()?;
+        }
+        if let Some(log_level) = cli.log_level {
+            config.log_level = log_level;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let file = File::open(path)
+            .map_err(|e| ConfigError::Unreadable(path.to_path_buf(), e.to_string()))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| ConfigError::Unparsable(path.to_path_buf(), e.to_string()))
+    }
+
+    fn resolve_against(&mut self, base_dir: &Path) {
+        let resolve = |path: &mut PathBuf| *path = base_dir.join(&*path);
+        if let Some(inbox) = self.inbox.as_mut() {
+            resolve(inbox);
+        }
+        for inbox in self.inboxes.iter_mut() {
+            resolve(&mut inbox.path);
+            if let Some(thumbnail_dir) = inbox.thumbnail_dir.as_mut() {
+                resolve(thumbnail_dir);
+            }
+        }
+        if let Some(state_dir) = self.state_dir.as_mut() {
+            resolve(state_dir);
+        }
+        resolve(&mut self.destinations);
+        resolve(&mut self.users);
+        resolve(&mut self.rules);
+        resolve(&mut self.frontend);
+        if let Some(thumbnail_dir) = self.thumbnail_dir.as_mut() {
+            resolve(thumbnail_dir);
+        }
+        if let Some(tls) = self.tls.as_mut() {
+            resolve(&mut tls.cert);
+            resolve(&mut tls.key);
+        }
+    }
+
+    fn validate(&mut self) -> Result<(), ConfigError> {
+        if let Some(inbox) = self.inbox.take() {
+            if !self.inboxes.is_empty() {
+                return Err(ConfigError::Invalid("Set either 'inbox' or 'inboxes', not both".to_string()));
+            }
+            let inbox = resolve_inbox(&inbox)?;
+            self.inboxes.push(InboxConfig {
+                name: inbox.file_name().and_then(|name| name.to_str()).unwrap_or("inbox").to_string(),
+                path: inbox,
+                thumbnail_dir: self.thumbnail_dir.take(),
+                destinations: None,
+            });
+        } else if self.thumbnail_dir.is_some() {
+            return Err(ConfigError::Invalid("'thumbnail_dir' only applies to 'inbox'; set it per entry of 'inboxes' instead".to_string()));
+        }
+        if self.inboxes.is_empty() {
+            return Err(ConfigError::Invalid("No inbox given; pass it as argument or set 'inbox' or 'inboxes' in the config file".to_string()));
+        }
+        for inbox in self.inboxes.iter_mut() {
+            inbox.path = resolve_inbox(&inbox.path)?;
+            if let Some(thumbnail_dir) = inbox.thumbnail_dir.as_mut() {
+                *thumbnail_dir = absolute(thumbnail_dir)?;
+            }
+        }
+        for (i, inbox) in self.inboxes.iter().enumerate() {
+            if inbox.name.is_empty() {
+                return Err(ConfigError::Invalid(format!("The inbox {:?} needs a name", inbox.path)));
+            }
+            for other in self.inboxes.iter().skip(i + 1) {
+                if other.name == inbox.name {
+                    return Err(ConfigError::Invalid(format!("There are several inboxes named '{}'", inbox.name)));
+                }
+                // a file below both would be picked up twice
+                if other.path.starts_with(&inbox.path) || inbox.path.starts_with(&other.path) {
+                    return Err(ConfigError::Invalid(format!("The inboxes '{}' and '{}' overlap", inbox.name, other.name)));
+                }
+            }
+        }
+        if let Some(state_dir) = self.state_dir.as_mut() {
+            *state_dir = absolute(state_dir)?;
+        }
+
+        if !self.destinations.is_file() {
+            return Err(ConfigError::Invalid(format!("The destinations file {:?} doesn't exist", self.destinations)));
+        }
+        if self.thumbnail_size == 0 || self.preview_max_size == 0 {
+            return Err(ConfigError::Invalid("'thumbnail_size' and 'preview_max_size' have to be positive".to_string()));
+        }
+        if let Some(tls) = &self.tls {
+            for path in [&tls.cert, &tls.key] {
+                if !path.is_file() {
+                    return Err(ConfigError::Invalid(format!("The TLS file {:?} doesn't exist", path)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        match &self.state_dir {
+            Some(dir) => dir.clone(),
+            None => self.inboxes.first().expect("The config wasn't validated!").path.join(".filebase")
+        }
+    }
+}
+
+impl InboxConfig {
+    pub fn thumbnail_dir(&self) -> PathBuf {
+        match &self.thumbnail_dir {
+            Some(dir) => dir.clone(),
+            None => self.path.join(".thumbnails")
+        }
+    }
+}
+
+/// The watchdogs report absolute paths, so everything else has to use them as well.
+fn resolve_inbox(path: &Path) -> Result<PathBuf, ConfigError> {
+    if !path.is_dir() {
+        return Err(ConfigError::Invalid(format!("The inbox {:?} is no directory", path)));
+    }
+    path.canonicalize()
+        .map_err(|e| ConfigError::Invalid(format!("Failed to resolve the inbox {:?}: {}", path, e)))
+}
+
+fn absolute(path: &Path) -> Result<PathBuf, ConfigError> {
+    std::path::absolute(path)
+        .map_err(|e| ConfigError::Invalid(format!("Failed to resolve {:?}: {}", path, e)))
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Unreadable(path, message) => write!(f, "Failed to read the config file {:?}: {}", path, message),
+            ConfigError::Unparsable(path, message) => write!(f, "Failed to parse the config file {:?}: {}", path, message),
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
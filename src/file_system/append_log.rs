@@ -0,0 +1,123 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use log::{debug, warn};
+
+use crate::file_system::{unblock, FileSystemError, Result};
+
+/// Logs are compacted once they hold more appended records than this or than twice the live records.
+const COMPACT_THRESHOLD: usize = 1000;
+
+/// A file of JSON records, one per line, that is only ever appended to while running
+/// and rewritten in one go when it should be compacted. While running, the file is only
+/// touched off the executor.
+pub struct AppendLog<R> {
+    writer: Arc<Mutex<BufWriter<File>>>,
+    appended: usize,
+    _record: PhantomData<R>,
+}
+
+impl<R: Serialize + DeserializeOwned + Send + 'static> AppendLog<R> {
+    /// Reads all records of the log at `path`. A missing file yields no records.
+    pub fn replay(path: &Path) -> Result<Vec<R>> {
+        let mut records = Vec::new();
+        if !path.exists() {
+            return Ok(records);
+        }
+
+        debug!("Replaying log {:?}", path);
+        let reader = BufReader::new(File::open(path)?);
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<R>(&line) {
+                Ok(record) => records.push(record),
+                // a crash while appending leaves a truncated last line; everything before it is still valid
+                Err(e) => warn!("Skipping unreadable line {} of {:?}: {}", line_no + 1, path, e)
+            }
+        }
+        Ok(records)
+    }
+
+    /// Atomically replaces the log at `path` with `records` and opens it for appending.
+    pub fn rewrite(path: &Path, records: &[R]) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let tmp_path = path.with_extension("compact");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for record in records {
+                Self::write_record(&mut writer, record)?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        std::fs::rename(&tmp_path, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(AppendLog {
+            writer: Arc::new(Mutex::new(BufWriter::new(file))),
+            appended: 0,
+            _record: PhantomData,
+        })
+    }
+
+    /// Like `rewrite`, for compacting the log while running.
+    pub async fn compact(path: PathBuf, records: Vec<R>) -> Result<Self> {
+        unblock(move || Self::rewrite(&path, &records)).await
+    }
+
+    pub async fn append(&mut self, record: &R) -> Result<()> {
+        let line = Self::to_line(record)?;
+        let writer = self.writer.clone();
+        unblock(move || {
+            let mut writer = writer.lock().expect("The log writer lock is poisoned!");
+            writeln!(writer, "{}", line)?;
+            writer.flush()?;
+            Ok(())
+        }).await?;
+        self.appended += 1;
+        Ok(())
+    }
+
+    /// Whether the records appended since the log was last rewritten outweigh the `live` ones it would be rewritten with.
+    pub fn should_compact(&self, live: usize) -> bool {
+        self.appended > COMPACT_THRESHOLD.max(2 * live)
+    }
+
+    fn write_record<W: Write>(writer: &mut W, record: &R) -> Result<()> {
+        writeln!(writer, "{}", Self::to_line(record)?)?;
+        Ok(())
+    }
+
+    fn to_line(record: &R) -> Result<String> {
+        serde_json::to_string(record)
+            .map_err(|e| FileSystemError::Other(e.to_string()))
+    }
+}
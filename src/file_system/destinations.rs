@@ -17,95 +17,600 @@
 use std::fs::File;
 use std::io::BufReader;
 use std::path::{PathBuf, Path};
+use std::sync::Arc;
+use std::time::SystemTime;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use tokio::sync::RwLock;
+use log::{debug, info, warn};
 
-use crate::file_system::{FileSystemError, Result};
+use crate::file_system::{unblock, FileSystemError, Result};
 use crate::file_system::model::MediaItemMetadata;
-use chrono::Datelike;
+use crate::file_system::template::PathTemplate;
+use crate::file_system::transfer;
 
 #[derive(Serialize, Deserialize)]
 pub struct FileSystemDestination {
-    pub id: u64,
+    pub id: String,
     pub name: String,
 }
 
-#[derive(Deserialize, Clone, Debug)]
-struct FileSystemDestinationInternal {
-    #[serde(skip)]
-    pub id: u64,
+/// What to do when the derived destination path is already taken
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionStrategy {
+    /// Refuse the move and report an error
+    #[default]
+    Fail,
+    /// Leave the item in the inbox
+    Skip,
+    /// Append a numeric suffix to the file name
+    Suffix,
+    /// Replace the existing file
+    Overwrite,
+    /// Treat a byte-identical existing file as the already confirmed item and trash the source;
+    /// differing files get a numeric suffix
+    Deduplicate,
+}
 
+/// A destination as it is kept in the destinations file and edited through the API
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DestinationSettings {
+    /// Stays the same when the destination is renamed or moved within the file, so that the UI,
+    /// the inboxes and the journal keep referring to it; derived from the name and written back
+    /// to the file if left out
+    #[serde(default)]
+    pub id: String,
     pub name: String,
-    base_path: PathBuf,
-    dynamic_bp_suffix: String,
+    pub base_path: PathBuf,
+    #[serde(default)]
+    pub dynamic_bp_suffix: String,
+    #[serde(default)]
+    pub on_collision: CollisionStrategy,
+    /// Compare checksums of source and copy before deleting the source
+    #[serde(default)]
+    pub verify: bool,
+    /// Disabled destinations are kept in the file but not offered for confirming
+    #[serde(default)]
+    pub disabled: bool,
 }
 
-impl FileSystemDestinationInternal {
-    pub fn derive_path(&self, item: &MediaItemMetadata) -> PathBuf {
-        // %year%
-        // %month%
-        // '/mnt/data/Pictures/%year%/%month%/'
-        let mut folder = if self.dynamic_bp_suffix.is_empty() {
-            self.base_path.clone()
-        }else{
-            let mut bp = self.base_path.clone();
-            let mut suffix = self.dynamic_bp_suffix.clone();
+/// The settings of one entry of the destinations file, or the id or name it could be told apart by and why it is broken
+type ParsedSettings = std::result::Result<DestinationSettings, (Option<String>, String)>;
 
-            if let Some(idx) = suffix.find("%year%") {
-                suffix.replace_range(idx..idx+6, item.creation_date.year().to_string().as_str())
-            }
+/// A destination as listed for managing the destinations
+#[derive(Serialize, Debug)]
+pub struct ConfiguredDestination {
+    #[serde(flatten)]
+    pub settings: DestinationSettings,
+    /// Why the destination currently can't be confirmed to, e.g. a missing base path
+    pub problem: Option<String>,
+}
 
-            if let Some(idx) = suffix.find("%month%") {
-                let month = format!("{:02}", item.creation_date.month());
-                suffix.replace_range(idx..idx+7, month.as_str())
+#[derive(Clone, Debug)]
+struct FileSystemDestinationInternal {
+    settings: DestinationSettings,
+    template: PathTemplate,
+    problem: Option<String>,
+}
+
+impl FileSystemDestinationInternal {
+    /// Fails for settings which can never work; a base path which is missing right now only
+    /// makes the destination unavailable until it shows up. Blocks while probing the base path.
+    fn new(mut settings: DestinationSettings) -> Result<Self> {
+        if settings.name.trim().is_empty() {
+            return Err(FileSystemError::InvalidParameters("a name is required".to_string()));
+        }
+        if settings.id.is_empty() {
+            settings.id = slug(&settings.name);
+            if settings.id.is_empty() {
+                return Err(FileSystemError::InvalidParameters("an id is required, as none can be derived from the name".to_string()));
             }
+            warn!("Destination '{}' has no id; Using '{}' until one is saved", settings.name, settings.id);
+        } else if !is_valid_id(&settings.id) {
+            return Err(FileSystemError::InvalidParameters(format!("the id '{}' may only contain letters, digits, '-' and '_'", settings.id)));
+        }
+        let template = PathTemplate::parse(&settings.dynamic_bp_suffix)?;
+        let mut destination = FileSystemDestinationInternal {
+            settings,
+            template,
+            problem: None,
+        };
+        let problem = probe(&destination.settings.base_path);
+        destination.set_problem(problem);
+        Ok(destination)
+    }
 
+    /// Takes the outcome of `probe`; returns whether the destination became available.
+    fn set_problem(&mut self, problem: Option<String>) -> bool {
+        let was_unavailable = self.problem.is_some();
+        self.problem = problem;
+        match &self.problem {
+            Some(problem) if !was_unavailable => warn!("Destination '{}' is unavailable: {}", self.settings.id, problem),
+            Some(_) => {}
+            None if was_unavailable => info!("Destination '{}' is available again", self.settings.id),
+            None => {}
+        }
+        was_unavailable && self.problem.is_none()
+    }
+
+    fn id(&self) -> &str {
+        &self.settings.id
+    }
+
+    pub fn derive_path(&self, item: &MediaItemMetadata) -> Result<PathBuf> {
+        // '/mnt/data/Pictures/%year%/%month%/'
+        let mut folder = if self.settings.dynamic_bp_suffix.is_empty() {
+            self.settings.base_path.clone()
+        }else{
+            let mut bp = self.settings.base_path.clone();
+            let suffix = self.template.render(item)?;
             bp.push(Path::new(&suffix));
             bp
         };
         folder.push(Path::new(item.name.as_str()));
-        folder
+        Ok(folder)
     }
 }
 
-pub struct FileSystemDestinations(Vec<FileSystemDestinationInternal>);
+/// The destinations of the destinations file. Changes made through the API are written back to
+/// it, and changes made to it are picked up by `reload_if_changed`.
+#[derive(Clone)]
+pub struct FileSystemDestinations(Arc<RwLock<FileSystemDestinationsInternal>>);
 
-impl FileSystemDestinations {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Self {
-        let file = File::open(path).expect("Failed to open the given file with FileSystemDestinations");
-        let reader = BufReader::new(file);
-        let items : Vec<FileSystemDestinationInternal> = serde_json::from_reader(reader).expect("Failed to parse the given FileSystemDestinations!");
+struct FileSystemDestinationsInternal {
+    path: PathBuf,
+    /// When the file was last read or written, to tell whether somebody else changed it since
+    modified: Option<SystemTime>,
+    destinations: Vec<FileSystemDestinationInternal>,
+    /// The invalid entries of the file as they are, so that writing the file keeps them
+    rejected: Vec<serde_json::Value>,
+    /// Why the file couldn't be read at all; it isn't written to until that is fixed
+    broken: Option<String>,
+}
 
-        let mut s = FileSystemDestinations(Vec::new());
-        for mut item in items {
-            item.id = s.0.len() as u64;
-            println!("Adding destination {:?}", item);
-            s.0.push(item);
+impl FileSystemDestinations {
+    /// Invalid destinations are reported and left out, and a file which can't be read at all yields
+    /// no destinations until it is fixed. Destinations whose base path is missing or not writable
+    /// are loaded but can't be confirmed to until that is fixed.
+    pub async fn from_file<P: AsRef<Path>>(path: P) -> Self {
+        let mut inner = FileSystemDestinationsInternal {
+            path: path.as_ref().to_path_buf(),
+            modified: None,
+            destinations: Vec::new(),
+            rejected: Vec::new(),
+            broken: None,
+        };
+        let path = inner.path.clone();
+        let (modified, read) = unblock(move || Ok((last_modified(&path), read_file(&path)))).await
+            .unwrap_or_else(|e| (None, Err(e)));
+        inner.adopt(modified, read);
+        for destination in inner.destinations.iter() {
+            info!("Adding destination {:?}", destination);
         }
 
-        s
+        FileSystemDestinations(Arc::new(RwLock::new(inner)))
     }
 
-    pub fn derive_using(&self, id: &u64, item: &MediaItemMetadata) -> Result<PathBuf> {
-        if let Some(dst) = self.0.get((*id) as usize) {
-            Ok(dst.derive_path(item))
-        } else {
-            Err(FileSystemError::UnknownId(id.clone()))
+    /// Rereads the file if it was modified since it was last read or written. The valid destinations
+    /// replace the current ones; if the file can't be read at all, the current ones are kept.
+    /// Otherwise checks again whether unavailable destinations became available.
+    /// Returns whether the destinations changed.
+    pub async fn reload_if_changed(&self) -> bool {
+        // reading the file and probing the base paths may block for a while, so it happens without the lock
+        let (path, known, unavailable) = {
+            let inner = self.0.read().await;
+            let unavailable = inner.destinations.iter()
+                .filter(|dst| dst.problem.is_some())
+                .map(|dst| (dst.id().to_string(), dst.settings.base_path.clone()))
+                .collect::<Vec<(String, PathBuf)>>();
+            (inner.path.clone(), inner.modified, unavailable)
+        };
+        let recheck = unblock(move || {
+            let modified = last_modified(&path);
+            if modified != known {
+                return Ok(Recheck::File(modified, read_file(&path)));
+            }
+            Ok(Recheck::BasePaths(unavailable.into_iter()
+                .map(|(id, base_path)| (id, probe(&base_path)))
+                .collect()))
+        }).await;
+
+        let mut inner = self.0.write().await;
+        if inner.modified != known {
+            // written through the API in the meantime; whatever else changed is looked at next time
+            return false;
+        }
+        match recheck {
+            Ok(Recheck::BasePaths(problems)) => {
+                let mut changed = false;
+                for (id, problem) in problems {
+                    if let Some(dst) = inner.destinations.iter_mut().find(|dst| dst.id() == id) {
+                        changed |= dst.set_problem(problem);
+                    }
+                }
+                changed
+            }
+            Ok(Recheck::File(modified, read)) => {
+                let reloaded = inner.adopt(modified, read);
+                if reloaded {
+                    info!("Reloaded {} destinations from {:?}", inner.destinations.len(), inner.path);
+                }
+                reloaded
+            }
+            Err(e) => {
+                warn!("Checking the destinations in {:?} failed: {:?}", inner.path, e);
+                false
+            }
         }
     }
 
-    pub fn list(&self) -> Vec<FileSystemDestination> {
-        self.0.iter()
-            .map(|e| FileSystemDestination::from(e))
+    pub async fn derive_using(&self, id: &str, item: &MediaItemMetadata) -> Result<PathBuf> {
+        self.0.read().await.get(id)?.derive_path(item)
+    }
+
+    pub async fn collision_strategy(&self, id: &str) -> Result<CollisionStrategy> {
+        Ok(self.0.read().await.get_usable(id)?.settings.on_collision)
+    }
+
+    pub async fn verifies(&self, id: &str) -> Result<bool> {
+        Ok(self.0.read().await.get(id)?.settings.verify)
+    }
+
+    pub async fn contains(&self, id: &str) -> bool {
+        self.0.read().await.get(id).is_ok()
+    }
+
+    /// The ids of the valid destinations in the order of the file.
+    pub async fn ids(&self) -> Vec<String> {
+        self.0.read().await.destinations.iter()
+            .map(|dst| dst.id().to_string())
+            .collect::<Vec<String>>()
+    }
+
+    pub async fn base_paths(&self) -> Vec<PathBuf> {
+        self.0.read().await.destinations.iter()
+            .map(|dst| dst.settings.base_path.clone())
+            .collect::<Vec<PathBuf>>()
+    }
+
+    /// Lists the destinations offered for confirming.
+    pub async fn list(&self) -> Vec<FileSystemDestination> {
+        self.0.read().await.destinations.iter()
+            .filter(|dst| !dst.settings.disabled && dst.problem.is_none())
+            .map(FileSystemDestination::from)
             .collect::<Vec<FileSystemDestination>>()
     }
+
+    /// Lists every destination including its settings, disabled and unavailable ones as well.
+    pub async fn list_settings(&self) -> Vec<ConfiguredDestination> {
+        self.0.read().await.destinations.iter()
+            .map(ConfiguredDestination::from)
+            .collect::<Vec<ConfiguredDestination>>()
+    }
+
+    pub async fn create(&self, settings: DestinationSettings) -> Result<ConfiguredDestination> {
+        let id = if settings.id.is_empty() { slug(&settings.name) } else { settings.id.clone() };
+        self.modify(&id, |destinations| {
+            destinations.push(DestinationSettings { id: id.clone(), ..settings });
+            Ok(())
+        }).await
+    }
+
+    /// The id can't be changed, as it is what other config files and the journal refer to.
+    pub async fn update(&self, id: &str, settings: DestinationSettings) -> Result<ConfiguredDestination> {
+        if !settings.id.is_empty() && settings.id != id {
+            return Err(FileSystemError::InvalidParameters(format!("The id of destination '{}' can't be changed", id)));
+        }
+        self.modify(id, |destinations| {
+            *find_settings(destinations, id)? = DestinationSettings { id: id.to_string(), ..settings };
+            Ok(())
+        }).await
+    }
+
+    pub async fn set_disabled(&self, id: &str, disabled: bool) -> Result<ConfiguredDestination> {
+        self.modify(id, |destinations| {
+            find_settings(destinations, id)?.disabled = disabled;
+            Ok(())
+        }).await
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        let mut inner = self.0.write().await;
+        inner.reload_if_modified().await?;
+        let mut settings = inner.settings();
+        let position = settings.iter().position(|dst| dst.id == id)
+            .ok_or_else(|| FileSystemError::UnknownDestination(id.to_string()))?;
+        settings.remove(position);
+        inner.replace(settings).await?;
+        info!("Deleted destination '{}'", id);
+        Ok(())
+    }
+
+    /// Applies `change` to the settings of all destinations and, if they all are still valid,
+    /// writes them to the file before using them. Returns the destination with the given id.
+    async fn modify<F>(&self, id: &str, change: F) -> Result<ConfiguredDestination>
+        where F: FnOnce(&mut Vec<DestinationSettings>) -> Result<()> {
+        let mut inner = self.0.write().await;
+        inner.reload_if_modified().await?;
+        let mut settings = inner.settings();
+        change(&mut settings)?;
+        inner.replace(settings).await?;
+        let changed = inner.get(id)?;
+        info!("Saved destination {:?}", changed);
+        Ok(ConfiguredDestination::from(changed))
+    }
+}
+
+impl FileSystemDestinationsInternal {
+    fn get(&self, id: &str) -> Result<&FileSystemDestinationInternal> {
+        self.destinations.iter()
+            .find(|dst| dst.id() == id)
+            .ok_or_else(|| FileSystemError::UnknownDestination(id.to_string()))
+    }
+
+    fn get_usable(&self, id: &str) -> Result<&FileSystemDestinationInternal> {
+        let destination = self.get(id)?;
+        if destination.settings.disabled {
+            return Err(FileSystemError::InvalidParameters(format!("The destination '{}' is disabled", id)));
+        }
+        if let Some(problem) = &destination.problem {
+            return Err(FileSystemError::InvalidParameters(format!("The destination '{}' is unavailable: {}", id, problem)));
+        }
+        Ok(destination)
+    }
+
+    fn settings(&self) -> Vec<DestinationSettings> {
+        self.destinations.iter()
+            .map(|dst| dst.settings.clone())
+            .collect::<Vec<DestinationSettings>>()
+    }
+
+    /// Takes over what was read from the file. If it couldn't be read at all, the current destinations
+    /// are kept, but the file isn't written to until it is fixed, and it is only read again once it
+    /// changes once more. Returns whether it was read.
+    fn adopt(&mut self, modified: Option<SystemTime>, read: Result<DestinationsFile>) -> bool {
+        match read {
+            Ok(file) => {
+                self.modified = file.modified;
+                self.destinations = file.destinations;
+                self.rejected = file.rejected;
+                self.broken = None;
+                true
+            }
+            Err(e) => {
+                warn!("Keeping the current destinations, as those in {:?} can't be read: {:?}", self.path, e);
+                self.modified = modified;
+                self.broken = Some(format!("{:?}", e));
+                false
+            }
+        }
+    }
+
+    /// Picks up changes made to the file since it was last read or written, so that a change made
+    /// through the API doesn't overwrite them. Fails with a conflict if the file can't be read.
+    async fn reload_if_modified(&mut self) -> Result<()> {
+        let path = self.path.clone();
+        let known = self.modified;
+        let (modified, read) = unblock(move || {
+            let modified = last_modified(&path);
+            if modified == known {
+                return Ok((modified, None));
+            }
+            Ok((modified, Some(read_file(&path))))
+        }).await?;
+        if let Some(read) = read {
+            if self.adopt(modified, read) {
+                info!("Reloaded {} destinations from {:?} before changing them", self.destinations.len(), self.path);
+            }
+        }
+        match &self.broken {
+            Some(problem) => Err(FileSystemError::Conflict(format!("The destinations in {:?} can't be read; Fix them first: {}", self.path, problem))),
+            None => Ok(())
+        }
+    }
+
+    /// Replaces the file through a temporary file, so a crash never leaves half of it behind.
+    /// The invalid entries of the file are kept, but `settings` all have to be valid.
+    async fn replace(&mut self, settings: Vec<DestinationSettings>) -> Result<()> {
+        let (destinations, problems) = unblock(move || Ok(validate(settings.into_iter().map(Ok).collect()))).await?;
+        if !problems.is_empty() {
+            let problems = problems.into_iter().map(|(_, problem)| problem).collect::<Vec<String>>();
+            return Err(FileSystemError::InvalidParameters(problems.join("; ")));
+        }
+        let mut entries = destinations.iter()
+            .map(|dst| serde_json::to_value(&dst.settings))
+            .collect::<serde_json::Result<Vec<serde_json::Value>>>()
+            .map_err(|e| FileSystemError::Other(e.to_string()))?;
+        entries.extend(self.rejected.iter().cloned());
+
+        let path = self.path.clone();
+        self.modified = unblock(move || {
+            write_file(&path, &entries)?;
+            Ok(last_modified(&path))
+        }).await?;
+        debug!("Wrote {} destinations to {:?}", destinations.len(), self.path);
+        self.destinations = destinations;
+        Ok(())
+    }
+}
+
+/// What `reload_if_changed` found out without holding the lock
+enum Recheck {
+    /// The file is unchanged; the problems of the destinations which were unavailable, by id
+    BasePaths(Vec<(String, Option<String>)>),
+    /// The file changed; when it was modified and what it holds
+    File(Option<SystemTime>, Result<DestinationsFile>),
+}
+
+/// What the destinations file holds
+struct DestinationsFile {
+    /// After the generated ids were written back, if they were
+    modified: Option<SystemTime>,
+    destinations: Vec<FileSystemDestinationInternal>,
+    rejected: Vec<serde_json::Value>,
+}
+
+/// Parses and checks each destination on its own; the broken ones are reported and left out.
+/// Entries without an id get the one derived from their name written back, so that it stays
+/// the same when they are renamed later on.
+fn read_file(path: &Path) -> Result<DestinationsFile> {
+    let mut modified = last_modified(path);
+    let file = File::open(path)
+        .map_err(|e| FileSystemError::IOError(format!("Failed to open the destinations in {:?}: {}", path, e)))?;
+    let reader = BufReader::new(file);
+    let mut entries : Vec<serde_json::Value> = serde_json::from_reader(reader)
+        .map_err(|e| FileSystemError::InvalidParameters(format!("Failed to parse the destinations in {:?}: {}", path, e)))?;
+
+    if assign_ids(&mut entries) {
+        match write_file(path, &entries) {
+            Ok(()) => {
+                info!("Wrote the ids derived from the destination names to {:?}", path);
+                modified = last_modified(path);
+            }
+            Err(e) => warn!("Failed to write the ids derived from the destination names to {:?}: {:?}", path, e),
+        }
+    }
+
+    let settings = entries.iter()
+        .map(|entry| {
+            let label = entry.get("id").or_else(|| entry.get("name")).and_then(|label| label.as_str()).map(str::to_string);
+            serde_json::from_value::<DestinationSettings>(entry.clone())
+                .map_err(|e| (label, e.to_string()))
+        })
+        .collect::<Vec<ParsedSettings>>();
+    let (destinations, problems) = validate(settings);
+    let mut rejected = Vec::<serde_json::Value>::with_capacity(problems.len());
+    for (position, problem) in problems {
+        warn!("Leaving out a destination of {:?}: {}", path, problem);
+        rejected.push(entries[position].clone());
+    }
+
+    Ok(DestinationsFile {
+        modified,
+        destinations,
+        rejected,
+    })
+}
+
+/// Replaces the file through a temporary file, so a crash never leaves half of it behind.
+fn write_file(path: &Path, entries: &[serde_json::Value]) -> Result<()> {
+    let mut content = serde_json::to_vec_pretty(entries)
+        .map_err(|e| FileSystemError::Other(e.to_string()))?;
+    content.push(b'\n');
+
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("destinations");
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    std::fs::write(&temp_path, content)?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Gives the entries without an id the one derived from their name. Returns whether any got one.
+fn assign_ids(entries: &mut [serde_json::Value]) -> bool {
+    let mut assigned = false;
+    for entry in entries.iter_mut() {
+        let Some(entry) = entry.as_object_mut() else {
+            continue;
+        };
+        if entry.get("id").is_some_and(|id| id.as_str() != Some("")) {
+            continue;
+        }
+        match entry.get("name").and_then(|name| name.as_str()).map(slug) {
+            Some(id) if !id.is_empty() => {
+                entry.insert("id".to_string(), serde_json::Value::String(id));
+                assigned = true;
+            }
+            _ => {}
+        }
+    }
+    assigned
+}
+
+/// Checks every destination on its own. Returns the valid ones and, by their position, what is
+/// wrong with the others, labelled by their id, name or position.
+fn validate(settings: Vec<ParsedSettings>) -> (Vec<FileSystemDestinationInternal>, Vec<(usize, String)>) {
+    let mut destinations = Vec::<FileSystemDestinationInternal>::with_capacity(settings.len());
+    let mut problems = Vec::<(usize, String)>::new();
+    for (position, settings) in settings.into_iter().enumerate() {
+        let label = |label: Option<&str>| match label.filter(|label| !label.is_empty()) {
+            Some(label) => format!("destination '{}'", label),
+            None => format!("destination #{}", position + 1),
+        };
+        let problem = match settings {
+            Ok(settings) => {
+                let name = settings.name.clone();
+                match FileSystemDestinationInternal::new(settings) {
+                    Ok(destination) if destinations.iter().any(|dst| dst.id() == destination.id()) =>
+                        format!("{}: the id '{}' is used more than once", label(Some(&name)), destination.id()),
+                    Ok(destination) => {
+                        destinations.push(destination);
+                        continue;
+                    }
+                    Err(FileSystemError::InvalidParameters(message)) => format!("{}: {}", label(Some(&name)), message),
+                    Err(e) => format!("{}: {:?}", label(Some(&name)), e),
+                }
+            }
+            Err((name, message)) => format!("{}: {}", label(name.as_deref()), message),
+        };
+        problems.push((position, problem));
+    }
+    (destinations, problems)
+}
+
+/// Why confirming to `base_path` would fail right now, if it would. Blocks on the file system.
+fn probe(base_path: &Path) -> Option<String> {
+    match transfer::check_writable(base_path) {
+        Ok(()) => None,
+        Err(FileSystemError::FileNotFound(_)) => Some(format!("The base path {:?} is no directory", base_path)),
+        Err(FileSystemError::IOError(message)) | Err(FileSystemError::StorageFull(message)) =>
+            Some(format!("The base path {:?} isn't writable: {}", base_path, message)),
+        Err(e) => Some(format!("Checking the base path {:?} failed: {:?}", base_path, e)),
+    }
+}
+
+fn find_settings<'a>(settings: &'a mut [DestinationSettings], id: &str) -> Result<&'a mut DestinationSettings> {
+    settings.iter_mut()
+        .find(|dst| dst.id == id)
+        .ok_or_else(|| FileSystemError::UnknownDestination(id.to_string()))
+}
+
+/// Turns a name like "Holidays 2021" into an id like "holidays-2021".
+fn slug(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.trim().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
+fn is_valid_id(id: &str) -> bool {
+    id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn last_modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|metadata| metadata.modified()).ok()
 }
 
 impl From<&FileSystemDestinationInternal> for FileSystemDestination {
     fn from(fsi: &FileSystemDestinationInternal) -> Self {
         FileSystemDestination {
-            id: fsi.id.clone(),
-            name: fsi.name.clone(),
+            id: fsi.settings.id.clone(),
+            name: fsi.settings.name.clone(),
+        }
+    }
+}
+
+impl From<&FileSystemDestinationInternal> for ConfiguredDestination {
+    fn from(fsi: &FileSystemDestinationInternal) -> Self {
+        ConfiguredDestination {
+            settings: fsi.settings.clone(),
+            problem: fsi.problem.clone(),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,81 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::file_system::model::{ItemResult, Job, MediaItemMetadata};
+
+/// How many events a slow subscriber may fall behind before it misses some
+const EVENT_BUFFER: usize = 256;
+
+/// Something that changed in the inbox, pushed to every connected client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InboxEvent {
+    ItemAdded { item: MediaItemMetadata },
+    /// The item's metadata changed, e.g. because it was renamed
+    ItemUpdated { item: MediaItemMetadata },
+    /// The item's file vanished from the inbox
+    ItemRemoved { id: u64 },
+    ThumbnailReady { id: u64 },
+    Confirmed { confirmed: ItemResult },
+    Discarded { id: u64 },
+    /// A background job was started, made progress or finished
+    JobUpdated { job: Job },
+    /// Destinations were added, changed or removed, through the API or in the destinations file
+    DestinationsChanged,
+}
+
+impl InboxEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InboxEvent::ItemAdded { .. } => "item_added",
+            InboxEvent::ItemUpdated { .. } => "item_updated",
+            InboxEvent::ItemRemoved { .. } => "item_removed",
+            InboxEvent::ThumbnailReady { .. } => "thumbnail_ready",
+            InboxEvent::Confirmed { .. } => "confirmed",
+            InboxEvent::Discarded { .. } => "discarded",
+            InboxEvent::JobUpdated { .. } => "job_updated",
+            InboxEvent::DestinationsChanged => "destinations_changed",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus(broadcast::Sender<InboxEvent>);
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        EventBus(sender)
+    }
+
+    pub fn publish(&self, event: InboxEvent) {
+        // sending only fails if nobody is listening, which is fine
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<InboxEvent> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
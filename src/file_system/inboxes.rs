@@ -0,0 +1,88 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::file_system::{FileSystemError, Result};
+use crate::file_system::model::MediaItemMetadata;
+use crate::file_system::similarity::ImageSignature;
+use crate::file_system::thumbnail::Thumbnails;
+
+/// Where an inbox is and what may be done with its items
+#[derive(Debug, Clone)]
+pub struct InboxSettings {
+    pub name: String,
+    pub path: PathBuf,
+    pub thumbnail_dir: PathBuf,
+    /// The destinations its items may be confirmed to; all of them if `None`
+    pub destinations: Option<Vec<String>>,
+}
+
+pub struct Inbox {
+    pub settings: InboxSettings,
+    pub thumbnails: Thumbnails,
+}
+
+/// The configured inboxes; there are only a handful, so they are simply searched in order.
+pub struct Inboxes(Vec<Inbox>);
+
+impl Inbox {
+    pub fn offers(&self, destination_id: &str) -> bool {
+        match &self.settings.destinations {
+            Some(ids) => ids.iter().any(|id| id == destination_id),
+            None => true
+        }
+    }
+}
+
+impl Inboxes {
+    pub fn open(settings: Vec<InboxSettings>, thumbnail_size: u32, preview_max_size: u32) -> Result<Self> {
+        let mut inboxes = Vec::<Inbox>::with_capacity(settings.len());
+        for settings in settings {
+            let thumbnails = Thumbnails::new(&settings.thumbnail_dir, thumbnail_size, preview_max_size)?;
+            inboxes.push(Inbox { settings, thumbnails });
+        }
+        Ok(Inboxes(inboxes))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Inbox> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Inbox> {
+        self.0.iter()
+            .find(|inbox| inbox.settings.name == name)
+            .ok_or_else(|| FileSystemError::UnknownInbox(name.to_string()))
+    }
+
+    /// The inbox holding `item`; items stored before inboxes were named are told apart by their path.
+    pub fn of(&self, item: &MediaItemMetadata) -> Result<&Inbox> {
+        self.0.iter()
+            .find(|inbox| inbox.settings.name == item.inbox)
+            .or_else(|| self.0.iter().find(|inbox| item.path.starts_with(&inbox.settings.path)))
+            .ok_or_else(|| FileSystemError::UnknownPath(item.path.clone()))
+    }
+
+    pub async fn signatures(&self) -> HashMap<u64, ImageSignature> {
+        let mut signatures = HashMap::new();
+        for inbox in self.0.iter() {
+            signatures.extend(inbox.thumbnails.signatures().await);
+        }
+        signatures
+    }
+}
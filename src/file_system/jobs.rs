@@ -0,0 +1,178 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use tokio::sync::{Notify, RwLock};
+use log::info;
+
+use crate::file_system::{FileSystemError, Result};
+use crate::file_system::model::{ItemResult, Job, JobKind, JobState};
+
+/// How many finished jobs are kept around for clients to look at
+const FINISHED_JOBS_KEPT: usize = 100;
+
+/// Confirm and discard batches waiting for or being processed by the job runner. Jobs only live in memory;
+/// whatever was still queued when the server stopped has to be submitted again.
+#[derive(Clone)]
+pub struct JobQueue(Arc<RwLock<JobQueueInternal>>, Arc<Notify>);
+
+struct JobQueueInternal {
+    next_id: u64,
+    /// Ordered by id, so the most recent jobs are at the back
+    jobs: VecDeque<Job>,
+    pending: VecDeque<u64>,
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        JobQueue(Arc::new(RwLock::new(JobQueueInternal {
+            next_id: 0,
+            jobs: VecDeque::new(),
+            pending: VecDeque::new(),
+        })), Arc::new(Notify::new()))
+    }
+
+    pub async fn submit(&self, kind: JobKind, ids: Vec<u64>) -> Job {
+        let job = self.0.write().await.submit(kind, ids);
+        info!("Queued job {} with {} items", job.id, job.total);
+        self.1.notify_one();
+        job
+    }
+
+    pub async fn get(&self, id: &u64) -> Result<Job> {
+        self.0.read().await.get(id).cloned()
+    }
+
+    /// Lists the most recent jobs first.
+    pub async fn list(&self, limit: usize) -> Vec<Job> {
+        self.0.read().await.jobs.iter().rev().take(limit).cloned().collect::<Vec<Job>>()
+    }
+
+    /// Queued jobs are cancelled right away; running ones stop before their next item.
+    pub async fn cancel(&self, id: &u64) -> Result<Job> {
+        self.0.write().await.cancel(id)
+    }
+
+    /// Waits for the next queued job and marks it as running.
+    pub async fn next(&self) -> Job {
+        loop {
+            if let Some(job) = self.0.write().await.start_next() {
+                return job;
+            }
+            self.1.notified().await;
+        }
+    }
+
+    pub async fn is_cancel_requested(&self, id: &u64) -> bool {
+        self.0.read().await.get(id).map(|job| job.cancel_requested).unwrap_or(true)
+    }
+
+    /// Stores the result of the next item of a running job and returns the job's updated state.
+    pub async fn record(&self, id: &u64, result: ItemResult) -> Result<Job> {
+        let mut inner = self.0.write().await;
+        let job = inner.get_mut(id)?;
+        job.processed += 1;
+        job.results.push(result);
+        Ok(job.clone())
+    }
+
+    pub async fn finish(&self, id: &u64, state: JobState) -> Result<Job> {
+        let mut inner = self.0.write().await;
+        let job = inner.get_mut(id)?;
+        job.state = state;
+        job.finished_on = Some(chrono::Utc::now());
+        info!("Job {} finished as {:?} after {} of {} items", job.id, job.state, job.processed, job.total);
+        Ok(job.clone())
+    }
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JobQueueInternal {
+    fn submit(&mut self, kind: JobKind, ids: Vec<u64>) -> Job {
+        let job = Job {
+            id: self.next_id,
+            kind,
+            state: JobState::Queued,
+            total: ids.len(),
+            processed: 0,
+            ids,
+            cancel_requested: false,
+            submitted_on: chrono::Utc::now(),
+            finished_on: None,
+            results: Vec::new(),
+        };
+        self.next_id += 1;
+        self.pending.push_back(job.id);
+        self.jobs.push_back(job.clone());
+        self.prune();
+        job
+    }
+
+    fn get(&self, id: &u64) -> Result<&Job> {
+        self.jobs.iter()
+            .find(|job| job.id == *id)
+            .ok_or(FileSystemError::UnknownId(*id))
+    }
+
+    fn get_mut(&mut self, id: &u64) -> Result<&mut Job> {
+        self.jobs.iter_mut()
+            .find(|job| job.id == *id)
+            .ok_or(FileSystemError::UnknownId(*id))
+    }
+
+    fn cancel(&mut self, id: &u64) -> Result<Job> {
+        let job = self.get_mut(id)?;
+        match job.state {
+            JobState::Queued => {
+                job.state = JobState::Cancelled;
+                job.finished_on = Some(chrono::Utc::now());
+            }
+            JobState::Running => job.cancel_requested = true,
+            _ => return Err(FileSystemError::InvalidParameters(format!("Job {} already finished", id)))
+        }
+        info!("Cancelling job {}", id);
+        let job = job.clone();
+        self.pending.retain(|pending| pending != id);
+        Ok(job)
+    }
+
+    fn start_next(&mut self) -> Option<Job> {
+        let id = self.pending.pop_front()?;
+        let job = self.get_mut(&id).expect("Pending job is unknown!");
+        job.state = JobState::Running;
+        Some(job.clone())
+    }
+
+    /// Forgets the oldest finished jobs once there are too many of them.
+    fn prune(&mut self) {
+        let mut finished = self.jobs.iter().filter(|job| job.finished_on.is_some()).count();
+        self.jobs.retain(|job| {
+            if finished > FINISHED_JOBS_KEPT && job.finished_on.is_some() {
+                finished -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
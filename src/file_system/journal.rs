@@ -0,0 +1,215 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use log::{debug, info, warn};
+
+use crate::file_system::{unblock, FileSystemError, Result};
+use crate::file_system::append_log::AppendLog;
+use crate::file_system::model::{JournalEntry, MediaItemMetadata};
+use crate::file_system::transfer;
+
+/// Records every confirmed move so it can be traced and undone later.
+#[derive(Clone)]
+pub struct OperationJournal(Arc<RwLock<OperationJournalInternal>>);
+
+#[derive(Serialize, Deserialize, Debug)]
+enum JournalRecord {
+    Confirmed(Box<JournalEntry>),
+    Undone(u64),
+}
+
+struct OperationJournalInternal {
+    entries: Vec<JournalEntry>,
+    journal_path: PathBuf,
+    log: AppendLog<JournalRecord>,
+}
+
+impl OperationJournal {
+    /// `destination_ids` are the ids of the destinations in the order of their file, which older
+    /// entries refer to them by.
+    pub fn open(journal_path: &Path, destination_ids: &[String]) -> Result<Self> {
+        Ok(OperationJournal(Arc::new(RwLock::new(OperationJournalInternal::open(journal_path, destination_ids)?))))
+    }
+
+    pub async fn record_confirm(&self, item: &MediaItemMetadata, destination_id: &str, destination_path: &Path, checksum: Option<String>) -> Result<()> {
+        self.0.write().await.record_confirm(item, destination_id, destination_path, checksum).await
+    }
+
+    /// Lists the most recent entries first.
+    pub async fn list(&self, limit: usize) -> Vec<JournalEntry> {
+        self.0.read().await.entries.iter().rev().take(limit).cloned().collect::<Vec<JournalEntry>>()
+    }
+
+    /// Moves the confirmed item back to where it came from and returns the undone entry;
+    /// the path of its item points at the restored file.
+    pub async fn undo(&self, item_id: &u64) -> Result<JournalEntry> {
+        let entry = self.0.read().await.latest(item_id)?;
+        let destination_path = entry.destination_path.clone();
+        if !unblock(move || Ok(destination_path.is_file())).await? {
+            return Err(FileSystemError::FileNotFound(entry.destination_path.clone()));
+        }
+
+        info!("Undoing confirm of '{:?}' to '{:?}'", entry.source_path, entry.destination_path);
+        let (src, dst) = (entry.destination_path.clone(), entry.source_path.clone());
+        unblock(move || transfer::move_file(&src, &dst)).await?;
+        self.0.write().await.forget(item_id).await
+    }
+}
+
+impl OperationJournalInternal {
+    fn open(journal_path: &Path, destination_ids: &[String]) -> Result<Self> {
+        let mut entries = Vec::<JournalEntry>::new();
+        for record in AppendLog::<JournalRecord>::replay(journal_path)? {
+            match record {
+                JournalRecord::Confirmed(entry) => entries.push(*entry),
+                JournalRecord::Undone(id) => entries.retain(|e| e.item.id != id),
+            }
+        }
+
+        // the rewrite below keeps the ids, so this only happens once
+        for entry in entries.iter_mut().filter(|e| !destination_ids.contains(&e.destination_id)) {
+            let position = match entry.destination_id.parse::<usize>() {
+                Ok(position) => position,
+                Err(_) => continue
+            };
+            match destination_ids.get(position) {
+                Some(id) => {
+                    debug!("Journal entry of item {} now refers to destination #{} as '{}'", entry.item.id, position, id);
+                    entry.destination_id = id.clone();
+                }
+                None => warn!("Journal entry of item {} refers to the unknown destination #{}", entry.item.id, position),
+            }
+        }
+
+        let log = AppendLog::rewrite(journal_path, &Self::snapshot(&entries))?;
+        info!("Restored {} entries from the operation journal", entries.len());
+
+        Ok(OperationJournalInternal {
+            entries,
+            journal_path: journal_path.to_path_buf(),
+            log,
+        })
+    }
+
+    /// The records a compacted journal consists of: one `Confirmed` per entry which wasn't undone.
+    fn snapshot(entries: &[JournalEntry]) -> Vec<JournalRecord> {
+        entries.iter()
+            .map(|entry| JournalRecord::Confirmed(Box::new(entry.clone())))
+            .collect::<Vec<JournalRecord>>()
+    }
+
+    async fn append(&mut self, record: JournalRecord) -> Result<()> {
+        self.log.append(&record).await?;
+        if self.log.should_compact(self.entries.len()) {
+            debug!("Compacting operation journal {:?}", self.journal_path);
+            // the record is on disk already, so the journal just keeps growing until the next try
+            match AppendLog::compact(self.journal_path.clone(), Self::snapshot(&self.entries)).await {
+                Ok(log) => self.log = log,
+                Err(e) => warn!("Compacting operation journal {:?} failed: {:?}", self.journal_path, e)
+            }
+        }
+        Ok(())
+    }
+
+    async fn record_confirm(&mut self, item: &MediaItemMetadata, destination_id: &str, destination_path: &Path, checksum: Option<String>) -> Result<()> {
+        let entry = JournalEntry {
+            item: item.clone(),
+            source_path: item.path.clone(),
+            destination_path: destination_path.to_path_buf(),
+            destination_id: destination_id.to_string(),
+            confirmed_on: chrono::Utc::now(),
+            checksum,
+        };
+        self.entries.push(entry.clone());
+        if let Err(e) = self.append(JournalRecord::Confirmed(Box::new(entry))).await {
+            self.entries.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn latest(&self, item_id: &u64) -> Result<JournalEntry> {
+        match self.entries.iter().rev().find(|e| e.item.id == *item_id) {
+            Some(entry) => Ok(entry.clone()),
+            None => Err(FileSystemError::UnknownId(*item_id))
+        }
+    }
+
+    async fn forget(&mut self, item_id: &u64) -> Result<JournalEntry> {
+        match self.entries.iter().rposition(|e| e.item.id == *item_id) {
+            Some(idx) => {
+                let mut entry = self.entries.remove(idx);
+                self.append(JournalRecord::Undone(*item_id)).await?;
+                entry.item.path = entry.source_path.clone();
+                Ok(entry)
+            }
+            None => Err(FileSystemError::UnknownId(*item_id))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn item(id: u64) -> MediaItemMetadata {
+        MediaItemMetadata {
+            id,
+            name: format!("{}.jpg", id),
+            inbox: "inbox".to_string(),
+            subfolder: String::new(),
+            mime: "image/jpeg".to_string(),
+            creation_date: chrono::Utc.ymd(2021, 3, 4).and_hms(5, 6, 7),
+            camera_make: None,
+            camera_model: None,
+            content_hash: None,
+            added_on: None,
+            path: PathBuf::from(format!("/inbox/{}.jpg", id)),
+        }
+    }
+
+    #[tokio::test]
+    async fn journal_is_compacted_while_running() {
+        let path = std::env::temp_dir().join(format!("filebase-journal-test-{}.jsonl", std::process::id()));
+        let journal = OperationJournal::open(&path, &[]).unwrap();
+        let destination = Path::new("/dst/0.jpg");
+        journal.record_confirm(&item(0), "dst", destination, None).await.unwrap();
+        for _ in 0..600 {
+            let mut internal = journal.0.write().await;
+            internal.record_confirm(&item(1), "dst", destination, None).await.unwrap();
+            internal.forget(&1).await.unwrap();
+        }
+
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines < 1000, "the journal still holds {} records", lines);
+        drop(journal);
+
+        let reopened = OperationJournal::open(&path, &[]).unwrap();
+        let entries = reopened.list(10).await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].item.id, 0);
+
+        let _r = std::fs::remove_file(&path);
+    }
+}
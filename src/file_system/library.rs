@@ -0,0 +1,250 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use log::{debug, info, warn};
+
+use crate::file_system::{unblock, Result};
+use crate::file_system::append_log::AppendLog;
+use crate::file_system::transfer;
+
+/// Content hashes of all files below the destinations, used to recognize items which already have been filed.
+#[derive(Clone)]
+pub struct LibraryIndex(Arc<RwLock<LibraryIndexInternal>>);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct IndexedFile {
+    path: PathBuf,
+    size: u64,
+    /// Modification time in milliseconds since the epoch; together with the size it decides whether to rehash
+    modified: i64,
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum LibraryRecord {
+    Indexed(IndexedFile),
+    Removed(PathBuf),
+}
+
+struct LibraryIndexInternal {
+    log_path: PathBuf,
+    files: HashMap<PathBuf, IndexedFile>,
+    hashes: HashMap<String, Vec<PathBuf>>,
+    log: AppendLog<LibraryRecord>,
+}
+
+impl LibraryIndex {
+    pub fn open(log_path: &Path) -> Result<Self> {
+        Ok(LibraryIndex(Arc::new(RwLock::new(LibraryIndexInternal::open(log_path)?))))
+    }
+
+    /// Returns a file below one of the destinations with the given content hash, as far as the
+    /// index knows; it may have vanished since the index was last rebuilt.
+    pub async fn lookup(&self, hash: &str) -> Option<PathBuf> {
+        self.0.read().await.hashes.get(hash)
+            .and_then(|paths| paths.first())
+            .cloned()
+    }
+
+    /// Like `lookup`, but only returns a file which still exists. Vanished ones stay in the index
+    /// until it is rebuilt.
+    pub async fn lookup_existing(&self, hash: &str) -> Result<Option<PathBuf>> {
+        let candidates = self.0.read().await.hashes.get(hash).cloned().unwrap_or_default();
+        unblock(move || Ok(candidates.into_iter().find(|path| path.is_file()))).await
+    }
+
+    /// Indexes a file which was just filed; `hash` saves rehashing if its content hash already is known.
+    pub async fn add(&self, path: &Path, hash: Option<String>) -> Result<()> {
+        let path = path.to_path_buf();
+        let file = unblock(move || {
+            let hash = match hash {
+                Some(hash) => hash,
+                None => transfer::content_hash(&path)?
+            };
+            IndexedFile::describe(&path, hash)
+        }).await?;
+        self.0.write().await.insert(file).await
+    }
+
+    pub async fn remove(&self, path: &Path) -> Result<()> {
+        self.0.write().await.remove(path).await
+    }
+
+    /// Walks all `roots` and brings the index in line with them. Files whose size and modification
+    /// time didn't change keep their hash, so only new or modified files are read.
+    pub async fn rebuild(&self, roots: Vec<PathBuf>) -> Result<()> {
+        let known = self.0.read().await.files.clone();
+        let scanned = unblock(move || {
+            let mut scanned = HashMap::<PathBuf, IndexedFile>::new();
+            for root in roots.iter() {
+                if roots.iter().any(|other| other != root && root.starts_with(other)) {
+                    // already covered by the enclosing root
+                    continue;
+                }
+                Self::scan_directory(root, &known, &mut scanned);
+            }
+            Ok(scanned)
+        }).await?;
+
+        self.0.write().await.replace(scanned).await
+    }
+
+    fn scan_directory(dir: &Path, known: &HashMap<PathBuf, IndexedFile>, scanned: &mut HashMap<PathBuf, IndexedFile>) {
+        let entries = match dir.read_dir() {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Reading library directory {:?} failed: {}", dir, e);
+                return;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                Self::scan_directory(&path, known, scanned);
+            } else if path.is_file() && !transfer::is_temporary(&path) {
+                match Self::index_file(&path, known.get(&path)) {
+                    Ok(file) => {
+                        scanned.insert(path, file);
+                    }
+                    Err(e) => warn!("Indexing library file {:?} failed: {:?}", path, e)
+                }
+            }
+        }
+    }
+
+    fn index_file(path: &Path, known: Option<&IndexedFile>) -> Result<IndexedFile> {
+        let (size, modified) = IndexedFile::stat(path)?;
+        match known {
+            Some(known) if known.size == size && known.modified == modified => Ok(known.clone()),
+            _ => {
+                debug!("Hashing library file {:?}", path);
+                IndexedFile::describe(path, transfer::content_hash(path)?)
+            }
+        }
+    }
+}
+
+impl IndexedFile {
+    fn describe(path: &Path, hash: String) -> Result<Self> {
+        let (size, modified) = Self::stat(path)?;
+        Ok(IndexedFile {
+            path: path.to_path_buf(),
+            size,
+            modified,
+            hash,
+        })
+    }
+
+    fn stat(path: &Path) -> Result<(u64, i64)> {
+        let metadata = path.metadata()?;
+        let modified = chrono::DateTime::<chrono::Utc>::from(metadata.modified()?);
+        Ok((metadata.len(), modified.timestamp_millis()))
+    }
+}
+
+impl LibraryIndexInternal {
+    fn open(log_path: &Path) -> Result<Self> {
+        let mut files = HashMap::<PathBuf, IndexedFile>::new();
+        for record in AppendLog::<LibraryRecord>::replay(log_path)? {
+            match record {
+                LibraryRecord::Indexed(file) => {
+                    files.insert(file.path.clone(), file);
+                }
+                LibraryRecord::Removed(path) => {
+                    files.remove(&path);
+                }
+            }
+        }
+
+        let log = AppendLog::rewrite(log_path, &Self::records(&files))?;
+        info!("Restored {} files of the library index", files.len());
+
+        Ok(LibraryIndexInternal {
+            log_path: log_path.to_path_buf(),
+            hashes: Self::index_hashes(&files),
+            files,
+            log,
+        })
+    }
+
+    async fn insert(&mut self, file: IndexedFile) -> Result<()> {
+        self.log.append(&LibraryRecord::Indexed(file.clone())).await?;
+        self.unlink(&file.path);
+        self.hashes.entry(file.hash.clone()).or_default().push(file.path.clone());
+        self.files.insert(file.path.clone(), file);
+        Ok(())
+    }
+
+    async fn remove(&mut self, path: &Path) -> Result<()> {
+        if self.unlink(path) {
+            self.log.append(&LibraryRecord::Removed(path.to_path_buf())).await?;
+        }
+        Ok(())
+    }
+
+    async fn replace(&mut self, mut scanned: HashMap<PathBuf, IndexedFile>) -> Result<()> {
+        // files confirmed while the scan was running
+        let confirmed = self.files.iter()
+            .filter(|(path, _)| !scanned.contains_key(*path))
+            .map(|(path, file)| (path.clone(), file.clone()))
+            .collect::<Vec<(PathBuf, IndexedFile)>>();
+        let confirmed = unblock(move || Ok(confirmed.into_iter().filter(|(path, _)| path.is_file()).collect::<Vec<(PathBuf, IndexedFile)>>())).await?;
+        scanned.extend(confirmed);
+
+        info!("Library index holds {} files", scanned.len());
+        self.log = AppendLog::compact(self.log_path.clone(), Self::records(&scanned)).await?;
+        self.hashes = Self::index_hashes(&scanned);
+        self.files = scanned;
+        Ok(())
+    }
+
+    /// Drops `path` from the in-memory index and reports whether it was known.
+    fn unlink(&mut self, path: &Path) -> bool {
+        match self.files.remove(path) {
+            Some(old) => {
+                if let Some(paths) = self.hashes.get_mut(&old.hash) {
+                    paths.retain(|p| p != path);
+                    if paths.is_empty() {
+                        self.hashes.remove(&old.hash);
+                    }
+                }
+                true
+            }
+            None => false
+        }
+    }
+
+    fn index_hashes(files: &HashMap<PathBuf, IndexedFile>) -> HashMap<String, Vec<PathBuf>> {
+        let mut hashes = HashMap::<String, Vec<PathBuf>>::new();
+        for file in files.values() {
+            hashes.entry(file.hash.clone()).or_default().push(file.path.clone());
+        }
+        hashes
+    }
+
+    fn records(files: &HashMap<PathBuf, IndexedFile>) -> Vec<LibraryRecord> {
+        files.values()
+            .map(|file| LibraryRecord::Indexed(file.clone()))
+            .collect::<Vec<LibraryRecord>>()
+    }
+}
@@ -0,0 +1,68 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OwnedMutexGuard;
+
+/// One lock per key, e.g. per item or destination, so operations on the same key queue up
+/// while everything else proceeds in parallel. Unused locks are dropped again.
+#[derive(Clone)]
+pub struct KeyedLocks<K: Eq + Hash + Clone>(Arc<Mutex<HashMap<K, Arc<tokio::sync::Mutex<()>>>>>);
+
+pub struct KeyedGuard<K: Eq + Hash + Clone> {
+    key: K,
+    locks: KeyedLocks<K>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<K: Eq + Hash + Clone> KeyedLocks<K> {
+    pub fn new() -> Self {
+        KeyedLocks(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    pub async fn lock(&self, key: K) -> KeyedGuard<K> {
+        let lock = self.0.lock().expect("Keyed locks are poisoned!")
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = lock.lock_owned().await;
+        KeyedGuard {
+            key,
+            locks: self.clone(),
+            guard: Some(guard),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for KeyedLocks<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone> Drop for KeyedGuard<K> {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self.locks.0.lock().expect("Keyed locks are poisoned!");
+        // nobody but the map holds the lock anymore, i.e. nobody is waiting for it
+        if locks.get(&self.key).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            locks.remove(&self.key);
+        }
+    }
+}
@@ -14,111 +14,703 @@
  * limitations under the License.
  */
 
+use std::collections::{HashMap, HashSet};
 use std::path::{PathBuf, Path};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::Semaphore;
 
 use serde::{Deserialize, Serialize};
+use log::{debug, info, warn};
 
-use crate::file_system::destinations::{FileSystemDestinations, FileSystemDestination};
-use crate::file_system::model::{MediaItemMetadata};
+use crate::file_system::events::{EventBus, InboxEvent};
+use crate::file_system::destinations::{CollisionStrategy, ConfiguredDestination, DestinationSettings, FileSystemDestinations, FileSystemDestination};
+use crate::file_system::inboxes::{Inboxes, InboxSettings};
+use crate::file_system::jobs::JobQueue;
+use crate::file_system::journal::OperationJournal;
+use crate::file_system::library::LibraryIndex;
+use crate::file_system::locks::KeyedLocks;
+use crate::file_system::rules::{Rule, Rules};
+use crate::file_system::model::{ItemOutcome, ItemResult, Job, JobKind, JobState, JournalEntry, ListedInbox, ListedMediaItem, MediaItemMetadata, SimilarItemGroup, SimulatedItem, TrashedMediaItem};
 use crate::file_system::storage::MediaItemMetadataStorage;
-use crate::file_system::thumbnail::Thumbnails;
+use crate::file_system::transfer::Placement;
+use crate::file_system::trash::Trash;
 
+pub mod destinations;
+pub mod events;
+pub mod inboxes;
 pub mod model;
+pub mod rules;
 pub mod watchdog;
+mod append_log;
 mod storage;
-mod destinations;
+mod jobs;
+mod journal;
+mod library;
+mod locks;
+mod raw;
+mod similarity;
+mod template;
 mod thumbnail;
+mod transfer;
+mod trash;
 
 type Result<T> = std::result::Result<T, FileSystemError>;
 
+/// How many jobs are processed at the same time
+const PARALLEL_JOBS: usize = 4;
+
+/// How often the destinations file is checked for changes
+const DESTINATION_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How often items are checked for being due to be confirmed by a rule
+const RULE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Items a rule failed to confirm are retried after `RULE_INTERVAL`, then twice as long each time up to this
+const RULE_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(6 * 60 * 60);
+
+/// Runs blocking file system work on tokio's blocking thread pool instead of the executor.
+async fn unblock<T, F>(f: F) -> Result<T>
+    where T: Send + 'static,
+          F: FnOnce() -> Result<T> + Send + 'static {
+    tokio::task::spawn_blocking(f).await
+        .map_err(|e| FileSystemError::Other(e.to_string()))?
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum FileSystemError {
     UnknownId(u64),
     UnknownPath(PathBuf),
+    UnknownInbox(String),
+    UnknownDestination(String),
     InvalidParameters(String),
     FileNotFound(PathBuf),
     IOError(String),
+    DestinationExists(String),
+    /// Somebody else changed what the request is based on in the meantime
+    Conflict(String),
+    /// The copy differs from its source, which therefore was kept
+    ChecksumMismatch(PathBuf),
+    /// The disk ran out of space
+    StorageFull(String),
     NoParentDirectory(PathBuf),
     ImageError(String),
     Other(String),
     MultipleErrors(Vec<FileSystemError>),
 }
 
+/// Every component guards its own state, so there is no lock around the whole file system.
+/// Moves of the same item and collision resolution within the same directory are serialized
+/// by `item_locks` and `directory_locks`; everything else proceeds in parallel.
 #[derive(Clone)]
 pub struct FileSystem(
-    Arc<RwLock<FileSystemInternal>>
+    Arc<FileSystemInternal>
 );
 
 impl FileSystem {
-    pub fn new<P: AsRef<Path>>(source_files: &Path, destination_config: P) -> Self {
-        FileSystem(Arc::new(RwLock::new(FileSystemInternal {
-            destinations: FileSystemDestinations::from_file(destination_config),
-            storage: MediaItemMetadataStorage::new(),
-            thumbnails: Thumbnails::new(source_files),
+    /// All inboxes share the state kept in `state_dir`, e.g. the trash and the journal.
+    pub async fn new<P: AsRef<Path>>(state_dir: &Path, inboxes: Vec<InboxSettings>, destination_config: P, rule_config: &Path, thumbnail_size: u32, preview_max_size: u32) -> Result<Self> {
+        let destinations = FileSystemDestinations::from_file(destination_config).await;
+        let rules = Rules::from_file(rule_config, |name| inboxes.iter().any(|inbox| inbox.name == name))?;
+        let ids = destinations.ids().await;
+        let known = ids.iter().cloned().collect::<HashSet<String>>();
+        rules.check_destinations(|id| known.contains(id));
+        for inbox in inboxes.iter() {
+            for id in inbox.destinations.iter().flatten() {
+                // it may still be added while running
+                if !destinations.contains(id).await {
+                    warn!("The inbox '{}' offers the unknown destination '{}'", inbox.name, id);
+                }
+            }
+        }
+        Ok(FileSystem(Arc::new(FileSystemInternal {
+            state_dir: state_dir.to_path_buf(),
+            destinations,
+            rules,
+            storage: MediaItemMetadataStorage::open(state_dir.join("storage.jsonl").as_path())?,
+            inboxes: Inboxes::open(inboxes, thumbnail_size, preview_max_size)?,
+            trash: Trash::open(state_dir.join("trash").as_path())?,
+            journal: OperationJournal::open(state_dir.join("journal.jsonl").as_path(), &ids)?,
+            library: LibraryIndex::open(state_dir.join("library.jsonl").as_path())?,
+            events: EventBus::new(),
+            jobs: JobQueue::new(),
+            item_locks: KeyedLocks::new(),
+            directory_locks: KeyedLocks::new(),
         })))
     }
 
-    pub async fn launch_watchdog(&self, monitoring_dir: &Path) -> std::thread::JoinHandle<std::result::Result<(), watchdog::FilesystemWatchdogError>> {
-        watchdog::FileSystemWatchdogBuilder::new(monitoring_dir,
-                                                 self.0.read().await.storage.clone(),
-                                                 self.0.read().await.thumbnails.clone(),
-        )
-            .launch()
+    /// Launches one watchdog per inbox; none of them looks into the caches or the state dir.
+    pub async fn launch_watchdogs(&self) -> Vec<std::thread::JoinHandle<std::result::Result<(), watchdog::FilesystemWatchdogError>>> {
+        let inner = &self.0;
+        let mut excluded_dirs = vec![inner.state_dir.clone()];
+        for inbox in inner.inboxes.iter() {
+            excluded_dirs.push(inbox.thumbnails.cache_dir().await);
+        }
+        inner.inboxes.iter().map(|inbox| {
+            let builder = watchdog::FileSystemWatchdogBuilder::new(&inbox.settings.name,
+                                                                   &inbox.settings.path,
+                                                                   inner.storage.clone(),
+                                                                   inbox.thumbnails.clone(),
+                                                                   inner.events.clone(),
+            );
+            excluded_dirs.iter()
+                .fold(builder, |builder, dir| builder.exclude(dir))
+                .launch()
+        }).collect()
+    }
+
+    /// Periodically purges items which have been in the trash for longer than `retention`.
+    pub async fn launch_trash_purger(&self, retention: chrono::Duration) -> tokio::task::JoinHandle<()> {
+        let trash = self.0.trash.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                info!("Purging trashed items older than {} days", retention.num_days());
+                if let Err(e) = trash.purge_trashed_before(chrono::Utc::now() - retention).await {
+                    warn!("Purging the trash failed: {:?}", e);
+                }
+            }
+        })
+    }
+
+    /// Periodically rehashes new and modified files below the destinations' base paths.
+    pub async fn launch_library_indexer(&self, period: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        let (library, destinations) = (self.0.library.clone(), self.0.destinations.clone());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let roots = destinations.base_paths().await;
+                info!("Indexing the library below {:?}", roots);
+                if let Err(e) = library.rebuild(roots).await {
+                    warn!("Indexing the library failed: {:?}", e);
+                }
+            }
+        })
+    }
+
+    /// Periodically checks the destinations file for changes made by hand and reloads it.
+    pub async fn launch_destination_reloader(&self) -> tokio::task::JoinHandle<()> {
+        let (destinations, events) = (self.0.destinations.clone(), self.0.events.clone());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(DESTINATION_RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                if destinations.reload_if_changed().await {
+                    events.publish(InboxEvent::DestinationsChanged);
+                }
+            }
+        })
+    }
+
+    /// Periodically submits confirm jobs for the items whose rule confirms them automatically and
+    /// whose grace period is over. An item is submitted again only once its job finished; items
+    /// still in the inbox then, e.g. because the destination was taken, are retried with a backoff
+    /// doubling from the rule interval up to `RULE_MAX_BACKOFF`.
+    pub async fn launch_rule_runner(&self) -> tokio::task::JoinHandle<()> {
+        let fs = self.clone();
+        tokio::spawn(async move {
+            let mut confirmations = AutoConfirmations::default();
+            let mut interval = tokio::time::interval(RULE_INTERVAL);
+            loop {
+                interval.tick().await;
+                confirmations.settle(&fs.0.jobs).await;
+                let due = match fs.0.due_for_auto_confirm().await {
+                    Ok(due) => due,
+                    Err(e) => {
+                        warn!("Applying the rules failed: {:?}", e);
+                        continue;
+                    }
+                };
+                confirmations.forget_all_but(due.values().flatten());
+                for (destination, ids) in due {
+                    let ids = ids.into_iter().filter(|id| confirmations.is_ready(id)).collect::<Vec<u64>>();
+                    if ids.is_empty() {
+                        continue;
+                    }
+                    info!("Rules confirm items {:?} to '{}'", ids, destination);
+                    match fs.submit_confirm(destination.clone(), ids.clone()).await {
+                        Ok(job) => confirmations.submitted(&job),
+                        Err(e) => {
+                            warn!("Confirming items {:?} to '{}' by rule failed: {:?}", ids, destination, e);
+                            ids.iter().for_each(|id| confirmations.hold_back(*id));
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Processes up to `PARALLEL_JOBS` submitted jobs at the same time, e.g. confirms to different destinations.
+    pub async fn launch_job_runner(&self) -> tokio::task::JoinHandle<()> {
+        let fs = self.clone();
+        tokio::spawn(async move {
+            let (jobs, events) = (fs.0.jobs.clone(), fs.0.events.clone());
+            let slots = Arc::new(Semaphore::new(PARALLEL_JOBS));
+            loop {
+                let slot = slots.clone().acquire_owned().await.expect("The job slots were closed!");
+                let job = jobs.next().await;
+                let (fs, jobs, events) = (fs.clone(), jobs.clone(), events.clone());
+                tokio::spawn(async move {
+                    info!("Starting job {}", job.id);
+                    events.publish(InboxEvent::JobUpdated { job: job.clone() });
+                    let state = fs.run_job(&jobs, &events, &job).await;
+                    match jobs.finish(&job.id, state).await {
+                        Ok(job) => events.publish(InboxEvent::JobUpdated { job }),
+                        Err(e) => warn!("Finishing job {} failed: {:?}", job.id, e)
+                    }
+                    drop(slot);
+                });
+            }
+        })
+    }
+
+    async fn run_job(&self, jobs: &JobQueue, events: &EventBus, job: &Job) -> JobState {
+        for id in job.ids.iter() {
+            if jobs.is_cancel_requested(&job.id).await {
+                return JobState::Cancelled;
+            }
+            let result = {
+                let inner = &self.0;
+                match &job.kind {
+                    JobKind::Confirm { destination } => match inner.destinations.collision_strategy(destination).await {
+                        Ok(strategy) => inner.confirm_item(destination, strategy, id).await,
+                        Err(e) => ItemResult::failed(*id, e)
+                    },
+                    JobKind::Discard => inner.discard_item(id).await
+                }
+            };
+            match jobs.record(&job.id, result).await {
+                Ok(job) => events.publish(InboxEvent::JobUpdated { job }),
+                Err(e) => warn!("Recording the result for item {} of job {} failed: {:?}", id, job.id, e)
+            }
+        }
+        JobState::Completed
+    }
+
+    pub async fn submit_confirm(&self, destination_id: String, ids: Vec<u64>) -> Result<Job> {
+        let inner = &self.0;
+        // reject unknown destinations right away instead of failing every item
+        inner.destinations.collision_strategy(&destination_id).await?;
+        Ok(inner.jobs.submit(JobKind::Confirm { destination: destination_id }, ids).await)
+    }
+
+    pub async fn submit_discard(&self, ids: Vec<u64>) -> Job {
+        self.0.jobs.submit(JobKind::Discard, ids).await
+    }
+
+    pub async fn job(&self, id: u64) -> Result<Job> {
+        self.0.jobs.get(&id).await
     }
 
-    pub async fn list_confirm_destinations(&self) -> Vec<FileSystemDestination> {
-        println!("Listing known confirm destinations");
-        self.0.read().await.destinations.list()
+    pub async fn list_jobs(&self, limit: usize) -> Vec<Job> {
+        self.0.jobs.list(limit).await
     }
 
-    pub async fn list(&self) -> Result<Vec<MediaItemMetadata>> {
-        self.0.read().await.list().await
+    pub async fn cancel_job(&self, id: u64) -> Result<Job> {
+        let inner = &self.0;
+        let job = inner.jobs.cancel(&id).await?;
+        inner.events.publish(InboxEvent::JobUpdated { job: job.clone() });
+        Ok(job)
+    }
+
+    pub async fn subscribe(&self) -> tokio::sync::broadcast::Receiver<InboxEvent> {
+        self.0.events.subscribe()
+    }
+
+    /// Lists the destinations offered for items of `inbox`, or all of them.
+    pub async fn list_confirm_destinations(&self, inbox: Option<String>) -> Result<Vec<FileSystemDestination>> {
+        debug!("Listing known confirm destinations");
+        let destinations = self.0.destinations.list().await;
+        match inbox {
+            Some(name) => {
+                let inbox = self.0.inboxes.get(&name)?;
+                Ok(destinations.into_iter().filter(|destination| inbox.offers(&destination.id)).collect())
+            }
+            None => Ok(destinations)
+        }
+    }
+
+    pub async fn list_inboxes(&self) -> Vec<ListedInbox> {
+        debug!("Listing inboxes");
+        let destinations = self.0.destinations.list().await;
+        self.0.inboxes.iter()
+            .map(|inbox| ListedInbox {
+                name: inbox.settings.name.clone(),
+                destinations: destinations.iter()
+                    .map(|destination| destination.id.clone())
+                    .filter(|id| inbox.offers(id))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    pub async fn list_destination_settings(&self) -> Vec<ConfiguredDestination> {
+        debug!("Listing the settings of all destinations");
+        self.0.destinations.list_settings().await
+    }
+
+    pub async fn create_destination(&self, settings: DestinationSettings) -> Result<ConfiguredDestination> {
+        info!("Creating destination '{}'", settings.name);
+        let created = self.0.destinations.create(settings).await?;
+        self.0.events.publish(InboxEvent::DestinationsChanged);
+        Ok(created)
+    }
+
+    pub async fn update_destination(&self, id: String, settings: DestinationSettings) -> Result<ConfiguredDestination> {
+        info!("Updating destination '{}'", id);
+        let updated = self.0.destinations.update(&id, settings).await?;
+        self.0.events.publish(InboxEvent::DestinationsChanged);
+        Ok(updated)
+    }
+
+    pub async fn set_destination_disabled(&self, id: String, disabled: bool) -> Result<ConfiguredDestination> {
+        info!("{} destination '{}'", if disabled { "Disabling" } else { "Enabling" }, id);
+        let updated = self.0.destinations.set_disabled(&id, disabled).await?;
+        self.0.events.publish(InboxEvent::DestinationsChanged);
+        Ok(updated)
+    }
+
+    pub async fn delete_destination(&self, id: String) -> Result<()> {
+        info!("Deleting destination '{}'", id);
+        self.0.destinations.delete(&id).await?;
+        self.0.events.publish(InboxEvent::DestinationsChanged);
+        Ok(())
+    }
+
+    pub async fn list(&self, subfolder: Option<String>, inbox: Option<String>) -> Result<Vec<ListedMediaItem>> {
+        self.0.list(subfolder, inbox).await
+    }
+
+    pub async fn list_rules(&self) -> Vec<Rule> {
+        self.0.rules.list()
+    }
+
+    /// Tells for every item of `inbox`, or of all inboxes, what the rules would do with it.
+    pub async fn simulate_rules(&self, inbox: Option<String>) -> Result<Vec<SimulatedItem>> {
+        let inner = &self.0;
+        debug!("Simulating the rules");
+        let mut simulated = Vec::<SimulatedItem>::new();
+        for item in inner.items_of(inbox.as_deref()).await? {
+            let suggestion = inner.rules.suggest(&item);
+            let (destination_path, problem) = match &suggestion {
+                Some(suggestion) => match inner.check_confirmable(&item, &suggestion.destination).await {
+                    Ok(()) => match inner.destinations.derive_using(&suggestion.destination, &item).await {
+                        Ok(path) => (Some(path), None),
+                        Err(e) => (None, Some(format!("{:?}", e)))
+                    },
+                    Err(e) => (None, Some(format!("{:?}", e)))
+                },
+                None => (None, None)
+            };
+            simulated.push(SimulatedItem {
+                id: item.id,
+                name: item.name,
+                inbox: item.inbox,
+                suggestion,
+                destination_path,
+                problem,
+            });
+        }
+        Ok(simulated)
+    }
+
+    /// Groups visually similar items of the same inbox whose creation dates are at most `max_gap` apart.
+    pub async fn group_similar(&self, max_distance: u32, max_gap: chrono::Duration, inbox: Option<String>) -> Result<Vec<SimilarItemGroup>> {
+        let inner = &self.0;
+        let (items, signatures) = (inner.items_of(inbox.as_deref()).await?, inner.inboxes.signatures().await);
+        debug!("Grouping similar items at most {} bits and {}s apart", max_distance, max_gap.num_seconds());
+        let mut items_by_inbox = HashMap::<String, Vec<MediaItemMetadata>>::new();
+        for item in items {
+            items_by_inbox.entry(item.inbox.clone()).or_default().push(item);
+        }
+        Ok(items_by_inbox.into_values()
+            .flat_map(|items| similarity::group_similar(items, &signatures, max_distance, max_gap))
+            .collect())
     }
 
     pub async fn read(&self, id: u64) -> Result<Vec<u8>> {
-        self.0.read().await.read(id).await
+        self.0.read(id).await
+    }
+
+    pub async fn read_original(&self, id: u64) -> Result<(MediaItemMetadata, tokio::fs::File)> {
+        self.0.read_original(id).await
+    }
+
+    pub async fn read_preview(&self, id: u64, width: Option<u32>, height: Option<u32>, quality: Option<u8>) -> Result<Vec<u8>> {
+        let inner = &self.0;
+        let item = inner.storage.get_item(&id).await?;
+        debug!("Reading requested preview {}", id);
+        inner.inboxes.of(&item)?.thumbnails.preview(&item, width, height, quality).await
     }
 
-    pub async fn discard(&self, ids: Vec<u64>) -> Result<()> {
-        self.0.write().await.discard(ids).await
+    pub async fn discard(&self, ids: Vec<u64>) -> Result<Vec<ItemResult>> {
+        self.0.discard(ids).await
     }
 
-    pub async fn discard_all(&self) -> Result<()> {
-        self.0.write().await.discard_all().await
+    pub async fn discard_all(&self, inbox: Option<String>) -> Result<Vec<ItemResult>> {
+        self.0.discard_all(inbox).await
     }
 
-    pub async fn confirm(&self, destination_id: &u64, ids: Vec<u64>) -> Result<()> {
-        self.0.write().await.confirm(destination_id, ids).await
+    pub async fn discard_filed(&self, inbox: Option<String>) -> Result<Vec<ItemResult>> {
+        self.0.discard_filed(inbox).await
+    }
+
+    pub async fn confirm(&self, destination_id: &str, ids: Vec<u64>) -> Result<Vec<ItemResult>> {
+        self.0.confirm(destination_id, ids).await
+    }
+
+    pub async fn list_trash(&self) -> Vec<TrashedMediaItem> {
+        self.0.list_trash().await
+    }
+
+    pub async fn list_journal(&self, limit: usize) -> Vec<JournalEntry> {
+        self.0.list_journal(limit).await
+    }
+
+    pub async fn undo(&self, ids: Vec<u64>) -> Result<()> {
+        self.0.undo(ids).await
+    }
+
+    pub async fn restore(&self, ids: Vec<u64>) -> Result<()> {
+        self.0.restore(ids).await
+    }
+
+    pub async fn purge(&self, ids: Vec<u64>) -> Result<()> {
+        self.0.purge(ids).await
+    }
+
+    pub async fn purge_all(&self) -> Result<()> {
+        self.0.purge_all().await
     }
 }
 
 struct FileSystemInternal {
+    state_dir: PathBuf,
     destinations: FileSystemDestinations,
+    rules: Rules,
     storage: MediaItemMetadataStorage,
-    thumbnails: Thumbnails,
+    inboxes: Inboxes,
+    trash: Trash,
+    journal: OperationJournal,
+    library: LibraryIndex,
+    events: EventBus,
+    jobs: JobQueue,
+    item_locks: KeyedLocks<u64>,
+    /// By the directory items are moved into, as destinations may share base paths
+    directory_locks: KeyedLocks<PathBuf>,
 }
 
 impl FileSystemInternal {
-    pub async fn list(&self) -> Result<Vec<MediaItemMetadata>> {
-        println!("Listing known items");
-        self.storage.list_files().await
+    /// The stored items of the inbox named `inbox`, or of all inboxes.
+    async fn items_of(&self, inbox: Option<&str>) -> Result<Vec<MediaItemMetadata>> {
+        let items = self.storage.list_files().await?;
+        match inbox {
+            Some(name) => {
+                self.inboxes.get(name)?;
+                Ok(items.into_iter().filter(|item| item.inbox == name).collect())
+            }
+            None => Ok(items)
+        }
+    }
+
+    pub async fn list(&self, subfolder: Option<String>, inbox: Option<String>) -> Result<Vec<ListedMediaItem>> {
+        debug!("Listing known items");
+        let items = self.items_of(inbox.as_deref()).await?;
+
+        // duplicates are looked for in the whole inbox, not only in the requested folder
+        let mut ids_by_hash = HashMap::<String, Vec<u64>>::new();
+        for item in items.iter() {
+            if let Some(hash) = &item.content_hash {
+                ids_by_hash.entry(hash.clone()).or_default().push(item.id);
+            }
+        }
+
+        let items = match subfolder {
+            Some(subfolder) => {
+                let subfolder = subfolder.trim_matches('/');
+                let nested_prefix = format!("{}/", subfolder);
+                items.into_iter()
+                    .filter(|item| item.subfolder == subfolder || item.subfolder.starts_with(&nested_prefix))
+                    .collect::<Vec<MediaItemMetadata>>()
+            }
+            None => items
+        };
+
+        let mut listed = Vec::<ListedMediaItem>::with_capacity(items.len());
+        for item in items {
+            let (duplicates, filed_at) = match &item.content_hash {
+                Some(hash) => {
+                    let duplicates = ids_by_hash.get(hash)
+                        .map(|ids| ids.iter().copied().filter(|id| *id != item.id).collect::<Vec<u64>>())
+                        .unwrap_or_default();
+                    (duplicates, self.library.lookup(hash).await)
+                }
+                None => (Vec::new(), None)
+            };
+            listed.push(ListedMediaItem {
+                suggestion: self.rules.suggest(&item),
+                item,
+                duplicates,
+                filed_at,
+            });
+        }
+        Ok(listed)
     }
 
     pub async fn read(&self, id: u64) -> Result<Vec<u8>> {
-        println!("Reading requested thumbnail {}", id);
-        self.thumbnails.get(&id).await
+        debug!("Reading requested thumbnail {}", id);
+        let item = self.storage.get_item(&id).await?;
+        self.inboxes.of(&item)?.thumbnails.get(&id).await
+    }
+
+    pub async fn read_original(&self, id: u64) -> Result<(MediaItemMetadata, tokio::fs::File)> {
+        debug!("Reading requested original {}", id);
+        let item = self.storage.get_item(&id).await?;
+        let file = tokio::fs::File::open(&item.path).await?;
+        Ok((item, file))
+    }
+
+    pub async fn discard(&self, ids: Vec<u64>) -> Result<Vec<ItemResult>> {
+        info!("Trying to discard items {:?}", ids);
+        let mut results = Vec::<ItemResult>::with_capacity(ids.len());
+        for id in ids {
+            results.push(self.discard_item(&id).await);
+        }
+        Ok(results)
+    }
+
+    pub async fn discard_item(&self, id: &u64) -> ItemResult {
+        let _item_lock = self.item_locks.lock(*id).await;
+        match self.storage.get_item(id).await {
+            Ok(item) => match self.discard_file(&item).await {
+                Ok(trash_path) => ItemResult::succeeded(*id, ItemOutcome::Discarded, trash_path),
+                Err(e) => ItemResult::failed(*id, e)
+            },
+            Err(e) => ItemResult::failed(*id, e)
+        }
+    }
+
+    pub async fn discard_all(&self, inbox: Option<String>) -> Result<Vec<ItemResult>> {
+        info!("Trying to discard all known items sequentially!");
+        match self.items_of(inbox.as_deref()).await {
+            Ok(metas) => {
+                let ids = metas.iter().map(|m| m.id).collect::<Vec<u64>>();
+                self.discard(ids).await
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Discards every item of which an identical copy already is below one of the destinations.
+    /// Only those items are reported; ones which turn out to differ from their copy are skipped.
+    pub async fn discard_filed(&self, inbox: Option<String>) -> Result<Vec<ItemResult>> {
+        info!("Trying to discard all items which already have been filed");
+        let mut results = Vec::<ItemResult>::new();
+        for item in self.items_of(inbox.as_deref()).await? {
+            let filed_at = match &item.content_hash {
+                Some(hash) => self.library.lookup_existing(hash).await?,
+                None => None
+            };
+            if let Some(filed_at) = filed_at {
+                let _item_lock = self.item_locks.lock(item.id).await;
+                // the item may have been moved while waiting for the lock
+                let item = match self.storage.get_item(&item.id).await {
+                    Ok(item) => item,
+                    Err(_) => continue
+                };
+                // the index may be stale, so only trust a byte-by-byte comparison before discarding
+                let (path, filed_path) = (item.path.clone(), filed_at.clone());
+                let result = match unblock(move || transfer::files_identical(&path, &filed_path)).await {
+                    Ok(true) => match self.discard_file(&item).await {
+                        Ok(trash_path) => ItemResult::succeeded(item.id, ItemOutcome::Discarded, trash_path),
+                        Err(e) => ItemResult::failed(item.id, e)
+                    },
+                    Ok(false) => {
+                        info!("Item {} differs from {:?} after all; Keeping it", item.id, filed_at);
+                        ItemResult::skipped(item.id)
+                    }
+                    Err(e) => ItemResult::failed(item.id, e)
+                };
+                results.push(result);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Fails as a whole only if the destination is unknown; every other failure is reported per item.
+    pub async fn confirm(&self, destination_id: &str, ids: Vec<u64>) -> Result<Vec<ItemResult>> {
+        info!("Trying to confirm items {:?} to '{}'", ids, destination_id);
+        let strategy = self.destinations.collision_strategy(destination_id).await?;
+        let mut results = Vec::<ItemResult>::with_capacity(ids.len());
+        for id in ids {
+            results.push(self.confirm_item(destination_id, strategy, &id).await);
+        }
+        Ok(results)
+    }
+
+    /// The items whose rule confirms them automatically and whose grace period is over, by destination.
+    async fn due_for_auto_confirm(&self) -> Result<HashMap<String, Vec<u64>>> {
+        let now = chrono::Utc::now();
+        let mut due = HashMap::<String, Vec<u64>>::new();
+        for item in self.storage.list_files().await? {
+            if let Some(suggestion) = self.rules.suggest(&item) {
+                if suggestion.auto_confirm_on.is_some_and(|on| on <= now) {
+                    due.entry(suggestion.destination).or_default().push(item.id);
+                }
+            }
+        }
+        Ok(due)
+    }
+
+    /// Fails if the item's inbox doesn't offer the destination.
+    async fn check_confirmable(&self, item: &MediaItemMetadata, destination_id: &str) -> Result<()> {
+        let inbox = self.inboxes.of(item)?;
+        if !inbox.offers(destination_id) {
+            return Err(FileSystemError::InvalidParameters(
+                format!("The inbox '{}' doesn't offer destination '{}'", inbox.settings.name, destination_id)));
+        }
+        self.destinations.collision_strategy(destination_id).await?;
+        Ok(())
+    }
+
+    pub async fn confirm_item(&self, destination_id: &str, strategy: CollisionStrategy, id: &u64) -> ItemResult {
+        let _item_lock = self.item_locks.lock(*id).await;
+        match self.storage.get_item(id).await {
+            Ok(item) => {
+                if let Err(e) = self.check_confirmable(&item, destination_id).await {
+                    return ItemResult::failed(*id, e);
+                }
+                match self.destinations.derive_using(destination_id, &item).await {
+                    Ok(dst_path) => {
+                        // collisions are resolved one item at a time per directory, so two items never pick the same free path
+                        let _directory_lock = self.directory_locks.lock(lock_key(&dst_path)).await;
+                        match self.confirm_file(destination_id, strategy, dst_path, &item).await {
+                            Ok(result) => result,
+                            Err(e) => ItemResult::failed(*id, e)
+                        }
+                    }
+                    Err(e) => ItemResult::failed(*id, e)
+                }
+            }
+            Err(e) => ItemResult::failed(*id, e)
+        }
+    }
+
+    pub async fn list_trash(&self) -> Vec<TrashedMediaItem> {
+        debug!("Listing trashed items");
+        self.trash.list().await
     }
 
-    pub async fn discard(&self, ids: Vec<u64>) -> Result<()> {
-        println!("Trying to discard items {:?}", ids);
+    pub async fn restore(&self, ids: Vec<u64>) -> Result<()> {
+        info!("Trying to restore items {:?}", ids);
         let mut failures = Vec::<FileSystemError>::new();
         for id in ids {
-            match self.storage.get_item(&id).await {
+            let _item_lock = self.item_locks.lock(id).await;
+            match self.trash.restore(&id).await {
                 Ok(item) => {
-                    if let Err(e) = self.discard_file(&item).await {
-                        failures.push(e)
+                    if let Err(e) = self.return_to_inbox(item).await {
+                        failures.push(e);
                     }
                 }
                 Err(e) => failures.push(e)
@@ -132,30 +724,45 @@ impl FileSystemInternal {
         }
     }
 
-    pub async fn discard_all(&self) -> Result<()> {
-        println!("Trying to discard all known items sequentially!");
-        match self.storage.list_files().await {
-            Ok(metas) => {
-                let ids = metas.iter().map(|m| m.id).collect::<Vec<u64>>();
-                self.discard(ids).await
-            },
-            Err(e) => Err(e)
+    pub async fn purge(&self, ids: Vec<u64>) -> Result<()> {
+        info!("Trying to purge items {:?}", ids);
+        let mut failures = Vec::<FileSystemError>::new();
+        for id in ids {
+            if let Err(e) = self.trash.purge(&id).await {
+                failures.push(e)
+            }
         }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(FileSystemError::MultipleErrors(failures))
+        }
+    }
+
+    pub async fn purge_all(&self) -> Result<()> {
+        info!("Trying to purge all trashed items!");
+        let ids = self.trash.list().await.iter().map(|t| t.item.id).collect::<Vec<u64>>();
+        self.purge(ids).await
     }
 
-    pub async fn confirm(&self, destination_id: &u64, ids: Vec<u64>) -> Result<()> {
-        println!("Trying to confirm items {:?} to {}", ids, destination_id);
+    pub async fn list_journal(&self, limit: usize) -> Vec<JournalEntry> {
+        debug!("Listing the last {} journal entries", limit);
+        self.journal.list(limit).await
+    }
+
+    pub async fn undo(&self, ids: Vec<u64>) -> Result<()> {
+        info!("Trying to undo the confirms of items {:?}", ids);
         let mut failures = Vec::<FileSystemError>::new();
         for id in ids {
-            match self.storage.get_item(&id).await {
-                Ok(item) => {
-                    match self.destinations.derive_using(destination_id, &item) {
-                        Ok(dst_path) => {
-                            if let Err(e) = self.confirm_file(dst_path.as_path(), &item).await {
-                                failures.push(e)
-                            }
-                        }
-                        Err(e) => failures.push(e)
+            let _item_lock = self.item_locks.lock(id).await;
+            match self.journal.undo(&id).await {
+                Ok(entry) => {
+                    if let Err(e) = self.library.remove(&entry.destination_path).await {
+                        warn!("Removing {:?} from the library index failed: {:?}", entry.destination_path, e);
+                    }
+                    if let Err(e) = self.return_to_inbox(entry.item).await {
+                        failures.push(e);
                     }
                 }
                 Err(e) => failures.push(e)
@@ -169,55 +776,168 @@ impl FileSystemInternal {
         }
     }
 
-    async fn discard_file(&self, item: &MediaItemMetadata) -> Result<()> {
+    /// Moves the item into the trash and returns where it is kept.
+    async fn discard_file(&self, item: &MediaItemMetadata) -> Result<PathBuf> {
         let p = &item.path;
-        if p.is_file() && p.exists() {
-            println!("Discarding '{:?}'", p);
-            std::fs::remove_file(p)?;
+        let path = p.clone();
+        if unblock(move || Ok(path.is_file())).await? {
+            info!("Discarding '{:?}'", p);
+            let trash_path = self.trash.put(item).await?;
             self.storage.remove_if_known(p).await;
-            self.thumbnails.remove(&item.id).await?;
-            Ok(())
+            self.events.publish(InboxEvent::Discarded { id: item.id });
+            self.remove_thumbnail(item).await;
+            Ok(trash_path)
         } else {
             Err(FileSystemError::FileNotFound(p.clone()))
         }
     }
 
-    async fn confirm_file(&self, destination_path: &Path, item: &MediaItemMetadata) -> Result<()> {
-        let src = &item.path;
-        let dst = destination_path;
+    /// Puts an item back under its id once its file is back in the inbox, e.g. after a restore or
+    /// an undo. Rules which confirm automatically give it a fresh grace period.
+    async fn return_to_inbox(&self, mut item: MediaItemMetadata) -> Result<()> {
+        item.added_on = Some(chrono::Utc::now());
+        self.storage.reinsert(item.clone()).await?;
+        self.events.publish(InboxEvent::ItemAdded { item: item.clone() });
+        match self.load_thumbnail(&item).await {
+            Ok(_) => self.events.publish(InboxEvent::ThumbnailReady { id: item.id }),
+            Err(e) => warn!("Generating thumbnail for returned item {} failed: {:?}", item.id, e)
+        }
+        Ok(())
+    }
 
-        if src.is_file() && !dst.exists() {
-            println!("Moving '{:?}' to '{:?}'", src, dst);
+    async fn load_thumbnail(&self, item: &MediaItemMetadata) -> Result<()> {
+        self.inboxes.of(item)?.thumbnails.load(item).await
+    }
 
-            match dst.parent() {
-                Some(parent_dir) => {
-                    if !parent_dir.exists() {
-                        println!("Missing destination directory; Creating");
-                        std::fs::create_dir_all(parent_dir)?
-                    }
-                }
-                None => return Err(FileSystemError::NoParentDirectory(dst.to_path_buf()))
+    /// Runs once the item was moved, which must not turn into a failure because of it, so problems
+    /// are only logged. Items the thumbnail generation failed for, e.g. unsupported formats, and
+    /// items of inboxes which were removed from the config have nothing to remove.
+    async fn remove_thumbnail(&self, item: &MediaItemMetadata) {
+        if let Ok(inbox) = self.inboxes.of(item) {
+            match inbox.thumbnails.remove(&item.id).await {
+                Ok(()) | Err(FileSystemError::UnknownId(_)) => {}
+                Err(e) => warn!("Failed to remove the thumbnail of item {}: {:?}", item.id, e),
             }
+        }
+    }
 
-            std::fs::copy(src, dst)?;
-            std::fs::remove_file(src)?;
-            self.storage.remove_if_known(src).await;
-            self.thumbnails.remove(&item.id).await?;
-            Ok(())
-        } else {
-            Err(FileSystemError::InvalidParameters(format!("Can't move '{:?}' to '{:?}'", src, dst)))
+    async fn confirm_file(&self, destination_id: &str, strategy: CollisionStrategy, destination_path: PathBuf, item: &MediaItemMetadata) -> Result<ItemResult> {
+        let verify = self.destinations.verifies(destination_id).await?;
+        let src = item.path.clone();
+        let (dst, outcome, checksum) = match unblock(move || transfer::place(&src, destination_path, strategy, verify)).await? {
+            Placement::Placed(dst, outcome, checksum) => (dst, outcome, checksum),
+            Placement::Conflict(dst) => return Err(FileSystemError::DestinationExists(format!(
+                "Can't move '{:?}' to '{:?}' as the file already exists; set 'on_collision' of destination '{}' to skip, suffix, overwrite or deduplicate",
+                item.path, dst, destination_id))),
+            Placement::Skipped => return Ok(ItemResult::skipped(item.id)),
+            Placement::Identical(dst) => {
+                info!("'{:?}' is identical to '{:?}'; Trashing the source", item.path, dst);
+                self.discard_file(item).await?;
+                return Ok(ItemResult::succeeded(item.id, ItemOutcome::AlreadyPresent, dst));
+            }
+        };
+
+        // the file was moved already, so the bookkeeping has to go on; only undoing it isn't possible then
+        if let Err(e) = self.journal.record_confirm(item, destination_id, &dst, checksum.clone()).await {
+            warn!("Recording the confirm of item {} to {:?} in the journal failed: {:?}", item.id, dst, e);
+        }
+        if let Err(e) = self.library.add(&dst, checksum.or_else(|| item.content_hash.clone())).await {
+            warn!("Adding {:?} to the library index failed: {:?}", dst, e);
         }
+        self.storage.remove_if_known(&item.path).await;
+        let confirmed = ItemResult::succeeded(item.id, outcome, dst);
+        self.events.publish(InboxEvent::Confirmed { confirmed: confirmed.clone() });
+        self.remove_thumbnail(item).await;
+        Ok(confirmed)
     }
 }
 
+/// The directory `path` is moved into, made absolute and without `.` components, so destinations
+/// spelling the same base path differently share a lock.
+fn lock_key(path: &Path) -> PathBuf {
+    let dir = path.parent().unwrap_or(path);
+    let absolute = if dir.is_absolute() {
+        dir.to_path_buf()
+    } else {
+        std::env::current_dir().map(|cwd| cwd.join(dir)).unwrap_or_else(|_| dir.to_path_buf())
+    };
+    absolute.components()
+        .filter(|component| !matches!(component, std::path::Component::CurDir))
+        .collect()
+}
+
 impl From<std::io::Error> for FileSystemError {
     fn from(e: std::io::Error) -> Self {
-        FileSystemError::IOError(e.to_string())
+        if e.kind() == std::io::ErrorKind::StorageFull {
+            FileSystemError::StorageFull(e.to_string())
+        } else {
+            FileSystemError::IOError(e.to_string())
+        }
     }
 }
 
 impl From<image::ImageError> for FileSystemError {
     fn from(e: image::ImageError) -> Self {
-        FileSystemError::ImageError(format!("{:?}", e))
+        match e {
+            // e.g. writing a thumbnail onto a full disk
+            image::ImageError::IoError(e) => e.into(),
+            e => FileSystemError::ImageError(format!("{:?}", e))
+        }
     }
-}
\ No newline at end of file
+}
+
+/// What the rule runner keeps track of between its runs: the job each submitted item is in, and
+/// how often and until when items are held back after a rule failed to confirm them.
+#[derive(Default)]
+struct AutoConfirmations {
+    submitted: HashMap<u64, u64>,
+    held_back: HashMap<u64, (u32, std::time::Instant)>,
+}
+
+impl AutoConfirmations {
+    fn submitted(&mut self, job: &Job) {
+        self.submitted.extend(job.ids.iter().map(|id| (*id, job.id)));
+    }
+
+    fn is_ready(&self, id: &u64) -> bool {
+        !self.submitted.contains_key(id)
+            && self.held_back.get(id).is_none_or(|(_, until)| *until <= std::time::Instant::now())
+    }
+
+    fn hold_back(&mut self, id: u64) {
+        let failures = self.held_back.get(&id).map_or(0, |(failures, _)| *failures) + 1;
+        let backoff = RULE_INTERVAL.saturating_mul(1 << (failures - 1).min(16)).min(RULE_MAX_BACKOFF);
+        debug!("Retrying to confirm item {} by rule in {:?}", id, backoff);
+        self.held_back.insert(id, (failures, std::time::Instant::now() + backoff));
+    }
+
+    /// Stops tracking the items of finished jobs; those which are still in the inbox afterwards are held back.
+    async fn settle(&mut self, jobs: &JobQueue) {
+        let job_ids = self.submitted.values().copied().collect::<HashSet<u64>>();
+        for job_id in job_ids {
+            let job = jobs.get(&job_id).await;
+            if job.as_ref().is_ok_and(|job| matches!(job.state, JobState::Queued | JobState::Running)) {
+                continue;
+            }
+            self.submitted.retain(|_, submitted_in| *submitted_in != job_id);
+            // a job dropped from the finished jobs already leaves nothing to hold back
+            let Ok(job) = job else {
+                continue;
+            };
+            for id in job.ids.iter() {
+                let moved = job.results.iter().any(|result| result.id == *id && result.path.is_some());
+                if moved {
+                    self.held_back.remove(id);
+                } else {
+                    self.hold_back(*id);
+                }
+            }
+        }
+    }
+
+    /// Drops what is known about items which aren't due anymore, e.g. because they were discarded.
+    fn forget_all_but<'a>(&mut self, due: impl Iterator<Item=&'a u64>) {
+        let due = due.collect::<HashSet<&u64>>();
+        self.held_back.retain(|id, _| due.contains(id));
+    }
+}
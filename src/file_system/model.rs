@@ -15,17 +15,217 @@
  */
 
 use std::path::PathBuf;
-use serde::{Serialize, Deserialize};
-use chrono::serde::ts_milliseconds;
+use serde::{Deserialize, Deserializer, Serialize};
+use chrono::serde::{ts_milliseconds, ts_milliseconds_option};
+
+use crate::file_system::FileSystemError;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MediaItemMetadata {
     pub id: u64,
     pub name: String,
+    /// The name of the inbox the item arrived in
+    #[serde(default)]
+    pub inbox: String,
+    /// The folder holding the item relative to the inbox; empty for items at the top level
+    #[serde(default)]
+    pub subfolder: String,
     pub mime: String,
     #[serde(with = "ts_milliseconds")]
     pub creation_date : chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub camera_make : Option<String>,
+    #[serde(default)]
+    pub camera_model : Option<String>,
+    /// Hex encoded SHA-256 of the file's content
+    #[serde(default)]
+    pub content_hash : Option<String>,
+    /// When the item arrived in the inbox
+    #[serde(default, with = "ts_milliseconds_option")]
+    pub added_on : Option<chrono::DateTime<chrono::Utc>>,
 
     #[serde(skip)]
     pub path : PathBuf,
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListedInbox {
+    pub name: String,
+    /// The ids of the destinations offered for its items
+    pub destinations: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListedMediaItem {
+    #[serde(flatten)]
+    pub item : MediaItemMetadata,
+    /// Other items in the inbox with the same content
+    pub duplicates : Vec<u64>,
+    /// A file with the same content below one of the destinations, as of the last library scan
+    pub filed_at : Option<PathBuf>,
+    /// Where the first matching rule would file the item
+    pub suggestion : Option<RuleSuggestion>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuleSuggestion {
+    pub rule : String,
+    pub destination : String,
+    /// When the item is going to be confirmed automatically, if the rule does that
+    #[serde(default, with = "ts_milliseconds_option")]
+    pub auto_confirm_on : Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// What the rules would do with an item
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimulatedItem {
+    pub id : u64,
+    pub name : String,
+    pub inbox : String,
+    pub suggestion : Option<RuleSuggestion>,
+    /// Where the item would end up
+    pub destination_path : Option<PathBuf>,
+    /// Why the item couldn't be filed as suggested
+    pub problem : Option<String>,
+}
+
+/// Visually similar items taken shortly after each other, e.g. the frames of a burst
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimilarItemGroup {
+    /// Ordered by creation date
+    pub ids : Vec<u64>,
+    /// The sharpest frame of the group
+    pub best : u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashedMediaItem {
+    #[serde(flatten)]
+    pub item : MediaItemMetadata,
+    #[serde(with = "ts_milliseconds")]
+    pub trashed_on : chrono::DateTime<chrono::Utc>,
+
+    #[serde(skip)]
+    pub trash_path : PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub item : MediaItemMetadata,
+    pub source_path : PathBuf,
+    pub destination_path : PathBuf,
+    #[serde(deserialize_with = "destination_id_or_position")]
+    pub destination_id : String,
+    #[serde(with = "ts_milliseconds")]
+    pub confirmed_on : chrono::DateTime<chrono::Utc>,
+    /// SHA-256 of the confirmed file, if its destination verifies moves
+    #[serde(default)]
+    pub checksum : Option<String>,
+}
+
+/// Journals written before destinations had ids of their own hold their position in the file instead;
+/// the journal maps those to ids when it is opened.
+fn destination_id_or_position<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum DestinationId {
+        Id(String),
+        Position(u64),
+    }
+    Ok(match DestinationId::deserialize(deserializer)? {
+        DestinationId::Id(id) => id,
+        DestinationId::Position(position) => position.to_string(),
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemOutcome {
+    Moved,
+    /// Moved under a suffixed name because the destination was taken
+    Renamed,
+    Overwritten,
+    /// An identical file already was at the destination; the source went to the trash
+    AlreadyPresent,
+    /// Moved to the trash
+    Discarded,
+    /// Nothing was done and the item stayed in the inbox, e.g. because the destination was taken
+    Skipped,
+    Failed,
+}
+
+/// What happened to a single item of a batch operation
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemResult {
+    pub id : u64,
+    pub outcome : ItemOutcome,
+    /// Where the item ended up; empty if it stayed in the inbox
+    pub path : Option<PathBuf>,
+    /// Why the item failed; the API reports it in its own error schema
+    #[serde(skip)]
+    pub error : Option<FileSystemError>,
+}
+
+impl ItemResult {
+    pub fn succeeded(id: u64, outcome: ItemOutcome, path: PathBuf) -> Self {
+        ItemResult {
+            id,
+            outcome,
+            path: Some(path),
+            error: None,
+        }
+    }
+
+    pub fn skipped(id: u64) -> Self {
+        ItemResult {
+            id,
+            outcome: ItemOutcome::Skipped,
+            path: None,
+            error: None,
+        }
+    }
+
+    pub fn failed(id: u64, error: FileSystemError) -> Self {
+        ItemResult {
+            id,
+            outcome: ItemOutcome::Failed,
+            path: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobKind {
+    Confirm { destination : String },
+    Discard,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Cancelled,
+}
+
+/// A confirm or discard batch processed in the background
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id : u64,
+    pub kind : JobKind,
+    pub state : JobState,
+    pub ids : Vec<u64>,
+    pub total : usize,
+    pub processed : usize,
+    pub cancel_requested : bool,
+    #[serde(with = "ts_milliseconds")]
+    pub submitted_on : chrono::DateTime<chrono::Utc>,
+    #[serde(default, with = "ts_milliseconds_option")]
+    pub finished_on : Option<chrono::DateTime<chrono::Utc>>,
+    /// One per processed item; the API reports them in its own schema
+    #[serde(skip)]
+    pub results : Vec<ItemResult>,
+}
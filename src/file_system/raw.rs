@@ -0,0 +1,85 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::io::Cursor;
+use std::path::Path;
+
+use image::codecs::jpeg::JpegDecoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat};
+use new_mime_guess::mime::Mime;
+use new_mime_guess::MimeGuess;
+use log::debug;
+
+use crate::file_system::{FileSystemError, Result};
+
+/// Camera RAW formats built on TIFF, so their EXIF data can be read like any other TIFF
+const RAW_MIME_TYPES: &[(&str, &str)] = &[
+    ("arw", "image/x-sony-arw"),
+    ("cr2", "image/x-canon-cr2"),
+    ("dng", "image/x-adobe-dng"),
+    ("nef", "image/x-nikon-nef"),
+    ("nrw", "image/x-nikon-nrw"),
+    ("pef", "image/x-pentax-pef"),
+    ("sr2", "image/x-sony-sr2"),
+];
+
+const JPEG_SOI: &[u8] = &[0xFF, 0xD8, 0xFF];
+
+/// Guesses the mime type from the file extension, knowing about camera RAW formats as well.
+pub fn guess_mime(path: &Path) -> Mime {
+    let raw_mime = path.extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| RAW_MIME_TYPES.iter().find(|(raw_ext, _)| raw_ext.eq_ignore_ascii_case(ext)))
+        .and_then(|(_, mime)| mime.parse::<Mime>().ok());
+
+    match raw_mime {
+        Some(mime) => mime,
+        None => MimeGuess::from_path(path).first_or_octet_stream()
+    }
+}
+
+pub fn is_raw(mime: &str) -> bool {
+    RAW_MIME_TYPES.iter().any(|(_, raw_mime)| *raw_mime == mime)
+}
+
+/// Decodes the largest JPEG preview embedded in a RAW file.
+///
+/// The camera writes at least one JPEG rendition next to the sensor data; rather than following the
+/// vendor specific IFD layout, every JPEG start marker is probed and the one with the largest frame wins.
+pub fn load_embedded_preview(path: &Path) -> Result<DynamicImage> {
+    let data = std::fs::read(path)?;
+
+    let mut best: Option<(usize, u64)> = None;
+    for offset in data.windows(JPEG_SOI.len()).enumerate().filter(|(_, w)| *w == JPEG_SOI).map(|(idx, _)| idx) {
+        // lossless JPEG sensor data and false positives fail to produce a header and are skipped
+        if let Ok(decoder) = JpegDecoder::new(Cursor::new(&data[offset..])) {
+            let (width, height) = decoder.dimensions();
+            let pixels = width as u64 * height as u64;
+            if best.is_none_or(|(_, best_pixels)| pixels > best_pixels) {
+                best = Some((offset, pixels));
+            }
+        }
+    }
+
+    match best {
+        Some((offset, _)) => {
+            debug!("Using the embedded preview at offset {} of {:?}", offset, path);
+            Ok(image::load_from_memory_with_format(&data[offset..], ImageFormat::Jpeg)?)
+        }
+        None => Err(FileSystemError::ImageError(format!("No embedded preview found in {:?}", path)))
+    }
+}
@@ -0,0 +1,307 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use log::{info, warn};
+
+use crate::file_system::{FileSystemError, Result};
+use crate::file_system::model::{MediaItemMetadata, RuleSuggestion};
+
+/// Which items a rule applies to; every condition which is given has to hold. Patterns may use
+/// `*` for any number of characters and `?` for exactly one; all comparisons ignore case.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConditions {
+    /// e.g. `image/*`
+    pub mime: Option<String>,
+    /// Without the dot, e.g. `["jpg", "jpeg"]`
+    pub extensions: Option<Vec<String>>,
+    /// e.g. `Pixel*`
+    pub camera_model: Option<String>,
+    /// e.g. `IMG_????.jpg`
+    pub filename: Option<String>,
+    pub inbox: Option<String>,
+    /// The first day of the creation dates, inclusive
+    pub taken_from: Option<NaiveDate>,
+    /// The last day of the creation dates, inclusive
+    pub taken_until: Option<NaiveDate>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Only show the destination next to the item
+    #[default]
+    Suggest,
+    /// Confirm the item once it has been in the inbox for the grace period
+    AutoConfirm,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(rename = "match", default)]
+    pub conditions: RuleConditions,
+    /// The id of the destination
+    pub destination: String,
+    #[serde(default)]
+    pub action: RuleAction,
+    /// Leaves time to intervene before an item is confirmed automatically
+    #[serde(default = "default_grace_minutes")]
+    pub grace_minutes: i64,
+}
+
+fn default_grace_minutes() -> i64 {
+    60
+}
+
+/// The rules of the rules file, e.g.
+///
+/// ```json
+/// [{"name": "Phone videos", "match": {"inbox": "phone", "mime": "video/*"}, "destination": "videos", "action": "auto_confirm"}]
+/// ```
+///
+/// For every item the first rule which matches it applies.
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// When items without an arrival time, e.g. stored by older versions, were first seen, so their grace period starts there
+    first_seen: Mutex<HashMap<u64, chrono::DateTime<chrono::Utc>>>,
+}
+
+impl Rules {
+    /// Without a rules file every item is confirmed by hand. Fails for rules which refer to an
+    /// inbox `is_inbox` doesn't know, as they could never match.
+    pub fn from_file(path: &Path, is_inbox: impl Fn(&str) -> bool) -> Result<Self> {
+        if !path.exists() {
+            info!("There are no rules at {:?}; Items are only confirmed by hand", path);
+            return Ok(Rules::new(Vec::new()));
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| FileSystemError::IOError(format!("Failed to read the rules in {:?}: {}", path, e)))?;
+        let rules: Vec<Rule> = serde_json::from_str(&content)
+            .map_err(|e| FileSystemError::InvalidParameters(format!("Failed to parse the rules in {:?}: {}", path, e)))?;
+        for rule in rules.iter() {
+            if rule.name.trim().is_empty() || rule.destination.is_empty() {
+                return Err(FileSystemError::InvalidParameters(format!("Every rule in {:?} needs a name and a destination", path)));
+            }
+            if rule.grace_minutes < 0 {
+                return Err(FileSystemError::InvalidParameters(format!("The grace period of rule '{}' is negative", rule.name)));
+            }
+            if let Some(inbox) = rule.conditions.inbox.as_deref().filter(|inbox| !is_inbox(inbox)) {
+                return Err(FileSystemError::InvalidParameters(format!("The rule '{}' refers to the unknown inbox '{}'", rule.name, inbox)));
+            }
+            info!("Adding rule {:?}", rule);
+        }
+        Ok(Rules::new(rules))
+    }
+
+    fn new(rules: Vec<Rule>) -> Self {
+        Rules {
+            rules,
+            first_seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Rule> {
+        self.rules.clone()
+    }
+
+    /// Warns about rules whose destination doesn't exist; it may still be added while running.
+    pub fn check_destinations(&self, known: impl Fn(&str) -> bool) {
+        for rule in self.rules.iter().filter(|rule| !known(&rule.destination)) {
+            warn!("The rule '{}' refers to the unknown destination '{}'", rule.name, rule.destination);
+        }
+    }
+
+    pub fn suggest(&self, item: &MediaItemMetadata) -> Option<RuleSuggestion> {
+        self.rules.iter()
+            .find(|rule| rule.matches(item))
+            .map(|rule| RuleSuggestion {
+                rule: rule.name.clone(),
+                destination: rule.destination.clone(),
+                auto_confirm_on: match rule.action {
+                    RuleAction::AutoConfirm => Some(self.arrival_of(item) + chrono::Duration::minutes(rule.grace_minutes)),
+                    RuleAction::Suggest => None
+                },
+            })
+    }
+
+    fn arrival_of(&self, item: &MediaItemMetadata) -> chrono::DateTime<chrono::Utc> {
+        match item.added_on {
+            Some(added_on) => added_on,
+            None => *self.first_seen.lock().expect("The first seen lock is poisoned!")
+                .entry(item.id)
+                .or_insert_with(chrono::Utc::now)
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, item: &MediaItemMetadata) -> bool {
+        let conditions = &self.conditions;
+        let extension = Path::new(&item.name).extension().and_then(|e| e.to_str()).unwrap_or_default();
+        let taken_on = item.creation_date.naive_utc().date();
+
+        conditions.mime.as_ref().is_none_or(|pattern| wildcard_match(pattern, &item.mime))
+            && conditions.extensions.as_ref().is_none_or(|extensions| extensions.iter().any(|e| e.eq_ignore_ascii_case(extension)))
+            && conditions.camera_model.as_ref().is_none_or(|pattern| item.camera_model.as_ref().is_some_and(|model| wildcard_match(pattern, model)))
+            && conditions.filename.as_ref().is_none_or(|pattern| wildcard_match(pattern, &item.name))
+            && conditions.inbox.as_ref().is_none_or(|inbox| *inbox == item.inbox)
+            && conditions.taken_from.is_none_or(|from| taken_on >= from)
+            && conditions.taken_until.is_none_or(|until| taken_on <= until)
+    }
+}
+
+/// Matches `text` against `pattern` with `*` and `?`, ignoring case.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<char>>();
+    let text = text.to_lowercase().chars().collect::<Vec<char>>();
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` was and how much of the text it swallowed so far
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn item(name: &str, mime: &str, model: Option<&str>) -> MediaItemMetadata {
+        MediaItemMetadata {
+            id: 0,
+            name: name.to_string(),
+            inbox: "phone".to_string(),
+            subfolder: String::new(),
+            mime: mime.to_string(),
+            creation_date: chrono::Utc.ymd(2021, 7, 14).and_hms(12, 0, 0),
+            camera_make: None,
+            camera_model: model.map(str::to_string),
+            content_hash: None,
+            added_on: None,
+            path: Default::default(),
+        }
+    }
+
+    fn rule(conditions: RuleConditions) -> Rule {
+        Rule {
+            name: "test".to_string(),
+            conditions,
+            destination: "dst".to_string(),
+            action: RuleAction::Suggest,
+            grace_minutes: default_grace_minutes(),
+        }
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> Option<NaiveDate> {
+        Some(NaiveDate::from_ymd(year, month, day))
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("image/*", "image/jpeg"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("IMG_????.jpg", "img_0001.JPG"));
+        assert!(!wildcard_match("IMG_????.jpg", "IMG_01.jpg"));
+        assert!(wildcard_match("*a*b*", "xxaxxbxx"));
+        assert!(wildcard_match("*.jpg", "a.jpg.jpg"));
+        assert!(!wildcard_match("*.jpg", "a.jpg.png"));
+        assert!(!wildcard_match("video/*", "image/jpeg"));
+        assert!(!wildcard_match("", "a"));
+    }
+
+    #[test]
+    fn empty_conditions_match_everything() {
+        assert!(rule(RuleConditions::default()).matches(&item("a.jpg", "image/jpeg", None)));
+    }
+
+    #[test]
+    fn every_condition_has_to_hold() {
+        let conditions = RuleConditions {
+            mime: Some("image/*".to_string()),
+            extensions: Some(vec!["jpg".to_string(), "jpeg".to_string()]),
+            camera_model: Some("Pixel*".to_string()),
+            inbox: Some("phone".to_string()),
+            ..RuleConditions::default()
+        };
+        let rule = rule(conditions);
+        assert!(rule.matches(&item("IMG_1.JPG", "image/jpeg", Some("Pixel 5"))));
+        assert!(!rule.matches(&item("IMG_1.png", "image/jpeg", Some("Pixel 5"))));
+        assert!(!rule.matches(&item("IMG_1.jpg", "video/mp4", Some("Pixel 5"))));
+        assert!(!rule.matches(&item("IMG_1.jpg", "image/jpeg", Some("X100"))));
+        // an item without a camera model never matches a pattern for it
+        assert!(!rule.matches(&item("IMG_1.jpg", "image/jpeg", None)));
+
+        let mut other_inbox = item("IMG_1.jpg", "image/jpeg", Some("Pixel 5"));
+        other_inbox.inbox = "camera".to_string();
+        assert!(!rule.matches(&other_inbox));
+    }
+
+    #[test]
+    fn taken_dates_are_inclusive() {
+        let matches = |taken_from, taken_until| rule(RuleConditions { taken_from, taken_until, ..RuleConditions::default() })
+            .matches(&item("a.jpg", "image/jpeg", None));
+        assert!(matches(date(2021, 7, 14), date(2021, 7, 14)));
+        assert!(matches(date(2021, 1, 1), None));
+        assert!(matches(None, date(2021, 12, 31)));
+        assert!(!matches(date(2021, 7, 15), None));
+        assert!(!matches(None, date(2021, 7, 13)));
+    }
+
+    #[test]
+    fn grace_period_of_items_without_arrival_time_starts_when_first_seen() {
+        let rules = Rules::new(vec![Rule {
+            action: RuleAction::AutoConfirm,
+            grace_minutes: 30,
+            ..rule(RuleConditions::default())
+        }]);
+        let before = chrono::Utc::now();
+        let first = rules.suggest(&item("a.jpg", "image/jpeg", None)).unwrap().auto_confirm_on.unwrap();
+        assert!(first >= before + chrono::Duration::minutes(30));
+        assert!(first <= chrono::Utc::now() + chrono::Duration::minutes(30));
+        // the grace period doesn't start over every time the rules are applied
+        let again = rules.suggest(&item("a.jpg", "image/jpeg", None)).unwrap().auto_confirm_on.unwrap();
+        assert_eq!(first, again);
+
+        let mut arrived = item("a.jpg", "image/jpeg", None);
+        arrived.id = 1;
+        arrived.added_on = Some(chrono::Utc.ymd(2021, 7, 14).and_hms(12, 0, 0));
+        assert_eq!(rules.suggest(&arrived).unwrap().auto_confirm_on, Some(chrono::Utc.ymd(2021, 7, 14).and_hms(12, 30, 0)));
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::file_system::model::{MediaItemMetadata, SimilarItemGroup};
+
+/// What is known about the look of an image, derived from its thumbnail.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageSignature {
+    /// Difference hash; visually similar images differ in few bits
+    pub perceptual_hash: u64,
+    /// Variance of the Laplacian; blurry frames score low
+    pub sharpness: f64,
+}
+
+impl ImageSignature {
+    pub fn of(image: &DynamicImage) -> Self {
+        ImageSignature {
+            perceptual_hash: difference_hash(image),
+            sharpness: sharpness(image),
+        }
+    }
+
+    pub fn distance(&self, other: &ImageSignature) -> u32 {
+        (self.perceptual_hash ^ other.perceptual_hash).count_ones()
+    }
+}
+
+/// Compares the brightness of neighbouring pixels of a 9x8 grayscale version of the image.
+fn difference_hash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+fn sharpness(image: &DynamicImage) -> f64 {
+    let gray = image.to_luma8();
+    let (width, height) = gray.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let mut responses = Vec::<f64>::with_capacity(((width - 2) * (height - 2)) as usize);
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let center = gray.get_pixel(x, y)[0] as f64;
+            let neighbours = gray.get_pixel(x - 1, y)[0] as f64
+                + gray.get_pixel(x + 1, y)[0] as f64
+                + gray.get_pixel(x, y - 1)[0] as f64
+                + gray.get_pixel(x, y + 1)[0] as f64;
+            responses.push(neighbours - 4.0 * center);
+        }
+    }
+
+    let mean = responses.iter().sum::<f64>() / responses.len() as f64;
+    responses.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / responses.len() as f64
+}
+
+/// Clusters items taken at most `max_gap` apart whose signatures differ in at most `max_distance` bits.
+/// Each item is compared with the latest member of the open groups, so slowly drifting bursts stay together.
+/// Only groups of at least two items are returned; the sharpest member is suggested as the one to keep.
+pub fn group_similar(mut items: Vec<MediaItemMetadata>, signatures: &HashMap<u64, ImageSignature>, max_distance: u32, max_gap: chrono::Duration) -> Vec<SimilarItemGroup> {
+    items.retain(|item| signatures.contains_key(&item.id));
+    items.sort_by_key(|item| item.creation_date);
+
+    let mut groups = Vec::<Vec<&MediaItemMetadata>>::new();
+    let mut open = Vec::<usize>::new();
+    for item in items.iter() {
+        let signature = &signatures[&item.id];
+        open.retain(|idx| {
+            let last = groups[*idx].last().expect("Groups are never empty!");
+            item.creation_date - last.creation_date <= max_gap
+        });
+
+        let matching = open.iter().copied().find(|idx| {
+            let last = groups[*idx].last().expect("Groups are never empty!");
+            signatures[&last.id].distance(signature) <= max_distance
+        });
+        match matching {
+            Some(idx) => groups[idx].push(item),
+            None => {
+                open.push(groups.len());
+                groups.push(vec![item]);
+            }
+        }
+    }
+
+    groups.into_iter()
+        .filter(|members| members.len() > 1)
+        .map(|members| {
+            let best = members.iter()
+                .max_by(|a, b| signatures[&a.id].sharpness.total_cmp(&signatures[&b.id].sharpness))
+                .map(|item| item.id)
+                .expect("Groups are never empty!");
+            SimilarItemGroup {
+                ids: members.iter().map(|item| item.id).collect::<Vec<u64>>(),
+                best,
+            }
+        })
+        .collect::<Vec<SimilarItemGroup>>()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use image::{GrayImage, Luma};
+
+    use super::*;
+
+    /// Brightens from left to right, or the other way round; `ripple` adds a fine checker pattern on top.
+    fn gradient(rising: bool, ripple: u8) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(90, 80, |x, y| {
+            let level = if rising { x * 2 } else { 180 - x * 2 } as u8 + 20;
+            let ripple = if (x + y) % 2 == 0 { ripple } else { 0 };
+            Luma([level.saturating_add(ripple)])
+        }))
+    }
+
+    fn item(id: u64, second: u32) -> MediaItemMetadata {
+        MediaItemMetadata {
+            id,
+            name: format!("{}.jpg", id),
+            inbox: "inbox".to_string(),
+            subfolder: String::new(),
+            mime: "image/jpeg".to_string(),
+            creation_date: chrono::Utc.ymd(2021, 3, 4).and_hms(5, 6, second),
+            camera_make: None,
+            camera_model: None,
+            content_hash: None,
+            added_on: None,
+            path: Default::default(),
+        }
+    }
+
+    #[test]
+    fn near_identical_images_have_close_hashes() {
+        let hash = difference_hash(&gradient(true, 0));
+        assert_eq!(hash, u64::MAX);
+        assert!((hash ^ difference_hash(&gradient(true, 10))).count_ones() <= 4);
+        assert!((hash ^ difference_hash(&gradient(true, 0).blur(2.0))).count_ones() <= 4);
+        assert!((hash ^ difference_hash(&gradient(false, 0))).count_ones() >= 60);
+    }
+
+    #[test]
+    fn blurring_lowers_the_sharpness() {
+        let sharp = sharpness(&gradient(true, 40));
+        let blurred = sharpness(&gradient(true, 40).blur(2.0));
+        assert!(sharp > blurred, "{} is not sharper than {}", sharp, blurred);
+        assert_eq!(sharpness(&DynamicImage::ImageLuma8(GrayImage::new(2, 2))), 0.0);
+    }
+
+    #[test]
+    fn bursts_are_grouped_with_the_sharpest_frame_as_best() {
+        let signatures = vec![
+            (1, gradient(true, 0).blur(2.0)),
+            (2, gradient(true, 40)),
+            (3, gradient(false, 0)),
+            (4, gradient(true, 0)),
+            (5, gradient(true, 0)),
+        ].into_iter()
+            .map(|(id, image)| (id, ImageSignature::of(&image)))
+            .collect::<HashMap<u64, ImageSignature>>();
+        // 3 looks different, 4 and 5 are too far apart from the burst and each other, and 6 has no thumbnail
+        let items = vec![item(2, 1), item(1, 0), item(3, 2), item(4, 40), item(5, 59), item(6, 1)];
+
+        let groups = group_similar(items, &signatures, 8, chrono::Duration::seconds(5));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].ids, vec![1, 2]);
+        assert_eq!(groups[0].best, 2);
+    }
+}
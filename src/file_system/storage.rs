@@ -14,21 +14,25 @@
  * limitations under the License.
  */
 
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
-use std::path::{Path, PathBuf};
-use std::collections::HashMap;
-use crate::file_system::model::MediaItemMetadata;
-use crate::file_system::{Result, FileSystemError};
+use log::{debug, info};
 
-type Dt = chrono::DateTime<chrono::Utc>;
+use crate::file_system::{FileSystemError, Result};
+use crate::file_system::append_log::AppendLog;
+use crate::file_system::model::MediaItemMetadata;
 
 #[derive(Clone)]
 pub struct MediaItemMetadataStorage(Arc<RwLock<MediaItemMetadataStorageInternal>>);
 
 impl MediaItemMetadataStorage {
-    pub fn new() -> Self {
-        MediaItemMetadataStorage(Arc::new(RwLock::new(MediaItemMetadataStorageInternal::new())))
+    pub fn open(journal_path: &Path) -> Result<Self> {
+        Ok(MediaItemMetadataStorage(Arc::new(RwLock::new(MediaItemMetadataStorageInternal::open(journal_path)?))))
     }
 
     pub async fn get_item(&self, id : &u64) -> Result<MediaItemMetadata> {
@@ -39,8 +43,23 @@ impl MediaItemMetadataStorage {
         self.0.read().await.is_path_known(path).await
     }
 
-    pub async fn add_file(&self, path : &Path, name : String, mime : String, creation_date : Dt) -> Result<MediaItemMetadata> {
-        self.0.write().await.add(path, name, mime, creation_date).await
+    pub async fn get_item_by_path(&self, path : &Path) -> Result<MediaItemMetadata> {
+        self.0.read().await.get_item_by_path(path).await
+    }
+
+    /// Stores a newly found item under a fresh id; the id `item` carries is ignored.
+    pub async fn add_file(&self, item : MediaItemMetadata) -> Result<MediaItemMetadata> {
+        self.0.write().await.add(item).await
+    }
+
+    /// Puts a previously removed item back under its original id.
+    pub async fn reinsert(&self, item : MediaItemMetadata) -> Result<()> {
+        self.0.write().await.reinsert(item).await
+    }
+
+    /// Replaces the stored metadata of an already known item, e.g. after it was renamed.
+    pub async fn update(&self, item : MediaItemMetadata) -> Result<()> {
+        self.0.write().await.update(item).await
     }
 
     pub async fn remove_file(&self, id : &u64) -> Result<()> {
@@ -61,20 +80,81 @@ impl MediaItemMetadataStorage {
     pub async fn list_files(&self) -> Result<Vec<MediaItemMetadata>> {
         self.0.read().await.list().await
     }
+
+    /// The items at `path` or anywhere below it.
+    pub async fn list_below(&self, path : &Path) -> Vec<MediaItemMetadata> {
+        self.0.read().await.list_below(path).await
+    }
+}
+
+/// A single line of the on-disk journal. The journal is replayed in order on startup;
+/// a later `Put` for the same id replaces the earlier one.
+#[derive(Serialize, Deserialize, Debug)]
+enum StorageRecord {
+    NextId(u64),
+    Put(Box<MediaItemMetadata>, PathBuf),
+    Removed(u64),
 }
 
 struct MediaItemMetadataStorageInternal {
     files : HashMap<u64, MediaItemMetadata>,
-    path_idx : HashMap<PathBuf, u64>,
-    next_id : u64
+    /// Ordered, so the items below a folder are adjacent
+    path_idx : BTreeMap<PathBuf, u64>,
+    next_id : u64,
+    journal_path : PathBuf,
+    journal : AppendLog<StorageRecord>,
 }
+
 impl MediaItemMetadataStorageInternal {
-    pub fn new() -> Self {
-        MediaItemMetadataStorageInternal {
-            files: HashMap::new(),
-            path_idx: HashMap::new(),
-            next_id: 0
+    pub fn open(journal_path : &Path) -> Result<Self> {
+        let mut files = HashMap::new();
+        let mut next_id = 0;
+
+        for record in AppendLog::<StorageRecord>::replay(journal_path)? {
+            match record {
+                StorageRecord::NextId(id) => next_id = next_id.max(id),
+                StorageRecord::Put(mut item, path) => {
+                    next_id = next_id.max(item.id + 1);
+                    item.path = path;
+                    files.insert(item.id, *item);
+                }
+                StorageRecord::Removed(id) => {
+                    files.remove(&id);
+                }
+            }
+        }
+
+        let path_idx = files.values()
+            .map(|item : &MediaItemMetadata| (item.path.clone(), item.id))
+            .collect::<BTreeMap<PathBuf, u64>>();
+
+        let journal = AppendLog::rewrite(journal_path, &Self::snapshot(next_id, &files))?;
+        info!("Restored {} items from storage; next id is {}", files.len(), next_id);
+
+        Ok(MediaItemMetadataStorageInternal {
+            files,
+            path_idx,
+            next_id,
+            journal_path: journal_path.to_path_buf(),
+            journal,
+        })
+    }
+
+    /// The records a compacted journal consists of: the next id and one `Put` per live item.
+    fn snapshot(next_id : u64, files : &HashMap<u64, MediaItemMetadata>) -> Vec<StorageRecord> {
+        let mut records = vec![StorageRecord::NextId(next_id)];
+        records.extend(files.values().map(|item| StorageRecord::Put(Box::new(item.clone()), item.path.clone())));
+        records
+    }
+
+    /// Appends `record` and compacts the journal once it grew well beyond the live items.
+    async fn append(&mut self, record : StorageRecord) -> Result<()> {
+        self.journal.append(&record).await?;
+        if self.journal.should_compact(self.files.len()) {
+            debug!("Compacting storage journal {:?}", self.journal_path);
+            self.journal = AppendLog::compact(self.journal_path.clone(), Self::snapshot(self.next_id, &self.files)).await?;
         }
+        Ok(())
     }
 
     pub async fn get_item(&self, id : &u64) -> Result<MediaItemMetadata> {
@@ -88,33 +168,71 @@ impl MediaItemMetadataStorageInternal {
         self.path_idx.contains_key(path)
     }
 
-    pub async fn add(&mut self, path : &Path, name : String, mime : String, creation_date : Dt) -> Result<MediaItemMetadata> {
+    pub async fn get_item_by_path(&self, path : &Path) -> Result<MediaItemMetadata> {
+        match self.path_idx.get(path) {
+            Some(id) => self.get_item(id).await,
+            None => Err(FileSystemError::UnknownPath(path.to_path_buf()))
+        }
+    }
+
+    pub async fn add(&mut self, item : MediaItemMetadata) -> Result<MediaItemMetadata> {
         debug_assert!(!self.files.contains_key(&self.next_id));
-        debug_assert!(!self.path_idx.contains_key(&path.to_path_buf()));
+        debug_assert!(!self.path_idx.contains_key(&item.path));
 
         let id = self.next_id;
         self.next_id += 1;
 
         let value = MediaItemMetadata{
-            id, name, mime, path: path.to_path_buf(), creation_date
+            id, ..item
         };
 
-        println!("Adding item {:?} to storage", value);
+        debug!("Adding item {:?} to storage", value);
 
         self.files.insert(id, value.clone());
-        self.path_idx.insert(path.to_path_buf(), id);
+        self.path_idx.insert(value.path.clone(), id);
+        self.append(StorageRecord::Put(Box::new(value.clone()), value.path.clone())).await?;
 
         Ok(value)
     }
 
+    pub async fn reinsert(&mut self, item : MediaItemMetadata) -> Result<()> {
+        if self.files.contains_key(&item.id) || self.path_idx.contains_key(&item.path) {
+            return Err(FileSystemError::InvalidParameters(format!("Item {} or its path {:?} is already known", item.id, item.path)));
+        }
+
+        debug!("Reinserting item {:?} into storage", item);
+
+        self.next_id = self.next_id.max(item.id + 1);
+        self.path_idx.insert(item.path.clone(), item.id);
+        self.files.insert(item.id, item.clone());
+        self.append(StorageRecord::Put(Box::new(item.clone()), item.path)).await
+    }
+
+    pub async fn update(&mut self, item : MediaItemMetadata) -> Result<()> {
+        let old_path = match self.files.get(&item.id) {
+            Some(known) => known.path.clone(),
+            None => return Err(FileSystemError::UnknownId(item.id))
+        };
+        if old_path != item.path && self.path_idx.contains_key(&item.path) {
+            return Err(FileSystemError::InvalidParameters(format!("Path {:?} is already known", item.path)));
+        }
+
+        debug!("Updating item {:?} in storage", item);
+
+        self.path_idx.remove(&old_path);
+        self.path_idx.insert(item.path.clone(), item.id);
+        self.files.insert(item.id, item.clone());
+        self.append(StorageRecord::Put(Box::new(item.clone()), item.path)).await
+    }
+
     pub async fn remove(&mut self, id : &u64) -> Result<()> {
-        match self.files.remove(&id) {
+        match self.files.remove(id) {
             Some(item) => {
                 self.path_idx.remove(&item.path).expect("Removing Item without removing from path index!");
-                println!("Removed item {:?} from storage", item);
-                Ok(())
+                debug!("Removed item {:?} from storage", item);
+                self.append(StorageRecord::Removed(*id)).await
             },
-            None => Err(FileSystemError::UnknownId(id.clone()))
+            None => Err(FileSystemError::UnknownId(*id))
         }
     }
 
@@ -122,8 +240,8 @@ impl MediaItemMetadataStorageInternal {
         match self.path_idx.remove(path) {
             Some(id) => {
                 self.files.remove(&id).expect("Removing Path without identifying the corresponding MediaItemMetadata!");
-                println!("Removed item {:?} using path {:?}", id, path);
-                Ok(())
+                debug!("Removed item {:?} using path {:?}", id, path);
+                self.append(StorageRecord::Removed(id)).await
             },
             None => {
                 Err(FileSystemError::UnknownPath(path.to_path_buf()))
@@ -132,6 +250,104 @@ impl MediaItemMetadataStorageInternal {
     }
 
     pub async fn list(&self) -> Result<Vec<MediaItemMetadata>> {
-        Ok(self.files.values().map(|it : &MediaItemMetadata| it.clone()).collect::<Vec<MediaItemMetadata>>())
+        Ok(self.files.values().cloned().collect::<Vec<MediaItemMetadata>>())
+    }
+
+    pub async fn list_below(&self, path : &Path) -> Vec<MediaItemMetadata> {
+        // paths order by their components, so everything below `path` directly follows it
+        self.path_idx.range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .take_while(|(item_path, _)| item_path.starts_with(path))
+            .filter_map(|(_, id)| self.files.get(id).cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn item(path: &str) -> MediaItemMetadata {
+        MediaItemMetadata {
+            id: 0,
+            name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            inbox: "inbox".to_string(),
+            subfolder: String::new(),
+            mime: "image/jpeg".to_string(),
+            creation_date: chrono::Utc.ymd(2021, 3, 4).and_hms(5, 6, 7),
+            camera_make: None,
+            camera_model: None,
+            content_hash: None,
+            added_on: None,
+            path: PathBuf::from(path),
+        }
+    }
+
+    #[tokio::test]
+    async fn list_below_covers_the_folder_and_nothing_next_to_it() {
+        let journal = std::env::temp_dir().join(format!("filebase-storage-test-{}.jsonl", std::process::id()));
+        let storage = MediaItemMetadataStorage::open(&journal).unwrap();
+        for path in ["/in/a", "/in/a/1.jpg", "/in/a/b/2.jpg", "/in/a-b/3.jpg", "/in/a.jpg", "/in/ab/4.jpg", "/in/5.jpg"] {
+            storage.add_file(item(path)).await.unwrap();
+        }
+
+        let mut below = storage.list_below(Path::new("/in/a")).await.into_iter()
+            .map(|item| item.path)
+            .collect::<Vec<PathBuf>>();
+        below.sort();
+        assert_eq!(below, vec![PathBuf::from("/in/a"), PathBuf::from("/in/a/1.jpg"), PathBuf::from("/in/a/b/2.jpg")]);
+        assert_eq!(storage.list_below(Path::new("/in/a/1.jpg")).await.len(), 1);
+        assert!(storage.list_below(Path::new("/out")).await.is_empty());
+
+        let _r = std::fs::remove_file(&journal);
+    }
+
+    /// Everything stored about the items, including the path which isn't serialized with them.
+    async fn stored(storage: &MediaItemMetadataStorage) -> Vec<(u64, serde_json::Value, PathBuf)> {
+        let mut stored = storage.list_files().await.unwrap().into_iter()
+            .map(|item| (item.id, serde_json::to_value(&item).unwrap(), item.path))
+            .collect::<Vec<(u64, serde_json::Value, PathBuf)>>();
+        stored.sort_by_key(|(id, _, _)| *id);
+        stored
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn items_keep_their_ids_and_metadata_across_restarts() {
+        let journal = std::env::temp_dir().join(format!("filebase-storage-restart-test-{}.jsonl", std::process::id()));
+        let storage = MediaItemMetadataStorage::open(&journal).unwrap();
+        let first = storage.add_file(item("/in/1.jpg")).await.unwrap();
+        let mut second = storage.add_file(item("/in/2.jpg")).await.unwrap();
+        let third = storage.add_file(item("/in/3.jpg")).await.unwrap();
+        second.camera_model = Some("X100".to_string());
+        second.content_hash = Some("abc".to_string());
+        second.path = PathBuf::from("/in/sub/2.jpg");
+        storage.update(second.clone()).await.unwrap();
+        storage.remove_file(&third.id).await.unwrap();
+        let before = stored(&storage).await;
+        assert_eq!(before.iter().map(|(id, _, _)| *id).collect::<Vec<u64>>(), vec![first.id, second.id]);
+        drop(storage);
+
+        let storage = MediaItemMetadataStorage::open(&journal).unwrap();
+        assert_eq!(stored(&storage).await, before);
+        // ids of removed items aren't handed out again
+        assert!(storage.add_file(item("/in/4.jpg")).await.unwrap().id > third.id);
+        storage.remove_path(Path::new("/in/4.jpg")).await.unwrap();
+
+        // enough records to compact the journal while running
+        for n in 0..1100 {
+            second.name = format!("{}.jpg", n);
+            storage.update(second.clone()).await.unwrap();
+        }
+        let lines = std::fs::read_to_string(&journal).unwrap().lines().count();
+        assert!(lines < 1000, "the journal still holds {} records", lines);
+        let before = stored(&storage).await;
+        drop(storage);
+
+        let storage = MediaItemMetadataStorage::open(&journal).unwrap();
+        assert_eq!(stored(&storage).await, before);
+        assert_eq!(storage.get_item_by_path(Path::new("/in/sub/2.jpg")).await.unwrap().name, "1099.jpg");
+
+        let _r = std::fs::remove_file(&journal);
+    }
+}
@@ -0,0 +1,301 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::path::{Component, Path};
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Datelike, TimeZone, Timelike};
+
+use crate::file_system::{FileSystemError, Result};
+use crate::file_system::model::MediaItemMetadata;
+
+/// A parsed `dynamic_bp_suffix` such as `%year%/%make|Unknown%/%date:%Y-%m-%d%/`.
+///
+/// Supported tokens are `%year%`, `%month%`, `%day%`, `%week%` (ISO week), `%quarter%`, `%hour%`,
+/// `%ext%`, `%mime%` (major mime type, e.g. `image`), `%make%` and `%model%`, as well as
+/// `%date:<strftime format>%`. The date format ends at the first `%` which doesn't start a
+/// strftime specifier, e.g. right before a `/`. Tokens for metadata which may be missing take
+/// a default after a pipe, e.g. `%model|Unknown%`; without one the path can't be derived.
+/// Neither the template nor a derived path may leave the base path, e.g. through `..` or a leading `/`.
+#[derive(Debug, Clone, Default)]
+pub struct PathTemplate(Vec<Segment>);
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Token(Token, Option<String>),
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Year,
+    Month,
+    Day,
+    Week,
+    Quarter,
+    Hour,
+    Extension,
+    MimeType,
+    Make,
+    Model,
+    Date(String),
+}
+
+const DATE_PREFIX: &str = "date:";
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut segments = Vec::<Segment>::new();
+        let mut literal = String::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('%') {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start + 1..];
+
+            let (token, default, remainder) = if let Some(after_prefix) = rest.strip_prefix(DATE_PREFIX) {
+                let (format, remainder) = Self::split_date_format(after_prefix)
+                    .ok_or_else(|| Self::error(template, "unterminated date token"))?;
+                if format.is_empty() {
+                    return Err(Self::error(template, "empty or unsupported date format"));
+                }
+                if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+                    return Err(Self::error(template, &format!("invalid date format '{}'", format)));
+                }
+                (Token::Date(format.to_string()), None, remainder)
+            } else {
+                let end = rest.find('%')
+                    .ok_or_else(|| Self::error(template, "unterminated token"))?;
+                let (name, default) = match rest[..end].split_once('|') {
+                    Some((name, default)) => (name, Some(default.to_string())),
+                    None => (&rest[..end], None)
+                };
+                (Self::parse_token(template, name)?, default, &rest[end + 1..])
+            };
+
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Token(token, default));
+            rest = remainder;
+        }
+
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        let parsed = PathTemplate(segments);
+        parsed.check_stays_inside(template)?;
+        Ok(parsed)
+    }
+
+    /// Rejects templates which leave the base path for any item: neither a default nor the
+    /// template rendered with sample values may be absolute or contain `..`.
+    fn check_stays_inside(&self, template: &str) -> Result<()> {
+        let sample_date = chrono::Utc.ymd(2000, 1, 1).and_hms(0, 0, 0);
+        let mut sample = String::new();
+        for segment in self.0.iter() {
+            match segment {
+                Segment::Literal(text) => sample.push_str(text),
+                Segment::Token(Token::Date(format), _) =>
+                    sample.push_str(&sample_date.format_with_items(StrftimeItems::new(format)).to_string()),
+                Segment::Token(_, default) => {
+                    if let Some(default) = default.as_deref().filter(|default| !stays_inside(default)) {
+                        return Err(Self::error(template, &format!("the default '{}' leaves the base path", default)));
+                    }
+                    sample.push('x');
+                }
+            }
+        }
+        if !stays_inside(&sample) {
+            return Err(Self::error(template, "it leaves the base path"));
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, item: &MediaItemMetadata) -> Result<String> {
+        let mut rendered = String::new();
+        for segment in self.0.iter() {
+            match segment {
+                Segment::Literal(text) => rendered.push_str(text),
+                Segment::Token(token, default) => {
+                    match (Self::value_of(token, item), default) {
+                        (Some(value), _) => rendered.push_str(&value),
+                        (None, Some(default)) => rendered.push_str(default),
+                        (None, None) => return Err(FileSystemError::InvalidParameters(
+                            format!("Item '{}' has no value for {:?} and the template gives no default", item.name, token)))
+                    }
+                }
+            }
+        }
+        // defaults and literals may still combine to e.g. `..`
+        if !stays_inside(&rendered) {
+            return Err(FileSystemError::InvalidParameters(
+                format!("The path '{}' derived for item '{}' leaves the base path", rendered, item.name)));
+        }
+        Ok(rendered)
+    }
+
+    fn value_of(token: &Token, item: &MediaItemMetadata) -> Option<String> {
+        let date = &item.creation_date;
+        match token {
+            Token::Year => Some(date.year().to_string()),
+            Token::Month => Some(format!("{:02}", date.month())),
+            Token::Day => Some(format!("{:02}", date.day())),
+            Token::Week => Some(format!("{:02}", date.iso_week().week())),
+            Token::Quarter => Some(((date.month() - 1) / 3 + 1).to_string()),
+            Token::Hour => Some(format!("{:02}", date.hour())),
+            Token::Date(format) => Some(date.format_with_items(StrftimeItems::new(format)).to_string()),
+            Token::Extension => Path::new(&item.name).extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.to_lowercase())
+                .map(|ext| Self::sanitize(&ext)),
+            Token::MimeType => item.mime.split('/').next().map(Self::sanitize),
+            Token::Make => item.camera_make.as_deref().map(Self::sanitize),
+            Token::Model => item.camera_model.as_deref().map(Self::sanitize),
+        }.filter(|value| !value.is_empty())
+    }
+
+    /// Metadata values must not be able to add folders or escape the base path.
+    fn sanitize(value: &str) -> String {
+        let value = value.trim().replace(['/', '\\'], "_");
+        if value == "." || value == ".." {
+            "_".to_string()
+        } else {
+            value
+        }
+    }
+
+    fn parse_token(template: &str, name: &str) -> Result<Token> {
+        match name {
+            "year" => Ok(Token::Year),
+            "month" => Ok(Token::Month),
+            "day" => Ok(Token::Day),
+            "week" => Ok(Token::Week),
+            "quarter" => Ok(Token::Quarter),
+            "hour" => Ok(Token::Hour),
+            "ext" => Ok(Token::Extension),
+            "mime" => Ok(Token::MimeType),
+            "make" => Ok(Token::Make),
+            "model" => Ok(Token::Model),
+            _ => Err(Self::error(template, &format!("unknown token '%{}%'", name)))
+        }
+    }
+
+    /// Splits `%Y-%m-%d%/rest` into the strftime format and whatever follows its closing `%`.
+    fn split_date_format(text: &str) -> Option<(&str, &str)> {
+        let mut idx = 0;
+        while let Some(offset) = text[idx..].find('%') {
+            let start = idx + offset;
+            // specifiers span up to four characters, e.g. `%.3f`, and never contain another `%`
+            let limit = text[start + 1..].find('%').map_or(text.len(), |o| start + 1 + o);
+            let specifier_end = (start + 2..=(start + 4).min(limit)).rev()
+                .filter(|end| text.is_char_boundary(*end))
+                .find(|end| !StrftimeItems::new(&text[start..*end]).any(|item| matches!(item, Item::Error)));
+            match specifier_end {
+                Some(end) => idx = end,
+                None => return Some((&text[..start], &text[start + 1..]))
+            }
+        }
+        None
+    }
+
+    fn error(template: &str, reason: &str) -> FileSystemError {
+        FileSystemError::InvalidParameters(format!("Invalid path template '{}': {}", template, reason))
+    }
+}
+
+/// Whether `path` stays below the folder it is appended to, i.e. has neither a root nor `..`.
+fn stays_inside(path: &str) -> bool {
+    Path::new(path).components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(model: Option<&str>) -> MediaItemMetadata {
+        MediaItemMetadata {
+            id: 0,
+            name: "IMG_0001.JPG".to_string(),
+            inbox: "inbox".to_string(),
+            subfolder: String::new(),
+            mime: "image/jpeg".to_string(),
+            creation_date: chrono::Utc.ymd(2021, 3, 4).and_hms(5, 6, 7),
+            camera_make: None,
+            camera_model: model.map(str::to_string),
+            content_hash: None,
+            added_on: None,
+            path: Default::default(),
+        }
+    }
+
+    fn render(template: &str, item: &MediaItemMetadata) -> Result<String> {
+        PathTemplate::parse(template)?.render(item)
+    }
+
+    #[test]
+    fn repeated_tokens() {
+        assert_eq!(render("%year%/%year%-%month%/%ext%%ext%", &item(None)).unwrap(), "2021/2021-03/jpgjpg");
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(render("%model|Unknown%/", &item(None)).unwrap(), "Unknown/");
+        assert_eq!(render("%model|Unknown%/", &item(Some("X100"))).unwrap(), "X100/");
+        assert_eq!(render("%model||%", &item(None)).unwrap(), "|");
+        assert!(render("%model%/", &item(None)).is_err());
+    }
+
+    #[test]
+    fn date_format_ends_before_the_slash() {
+        assert_eq!(render("%date:%Y-%m-%d%/", &item(None)).unwrap(), "2021-03-04/");
+        assert_eq!(render("%date:%Y%%month%", &item(None)).unwrap(), "202103");
+    }
+
+    #[test]
+    fn unterminated_tokens() {
+        assert!(PathTemplate::parse("%year").is_err());
+        assert!(PathTemplate::parse("%year%/%model|Unknown").is_err());
+        assert!(PathTemplate::parse("%date:%Y-%m").is_err());
+    }
+
+    #[test]
+    fn unknown_tokens() {
+        assert!(PathTemplate::parse("%yaer%").is_err());
+        assert!(PathTemplate::parse("%%").is_err());
+        assert!(PathTemplate::parse("%date:%").is_err());
+    }
+
+    #[test]
+    fn templates_leaving_the_base_path() {
+        assert!(PathTemplate::parse("../%year%").is_err());
+        assert!(PathTemplate::parse("%year%/../x").is_err());
+        assert!(PathTemplate::parse("/srv/%year%").is_err());
+        assert!(PathTemplate::parse("%model|..%").is_err());
+        assert!(PathTemplate::parse("%model|/srv%").is_err());
+        assert!(PathTemplate::parse("%date:%Y/..%").is_err());
+        assert!(PathTemplate::parse("./%year%/%month%").is_ok());
+    }
+
+    #[test]
+    fn rendered_paths_leaving_the_base_path() {
+        assert!(render(".%model|.%/", &item(None)).is_err());
+        assert_eq!(render(".%model|.%/", &item(Some("X"))).unwrap(), ".X/");
+        assert_eq!(render("%model%/", &item(Some(".."))).unwrap(), "_/");
+    }
+}
@@ -14,100 +14,327 @@
  * limitations under the License.
  */
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use image::ImageFormat;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
 use tokio::sync::RwLock;
+use log::{debug, info, warn};
 
-use crate::file_system::{FileSystemError, Result};
+use crate::file_system::{unblock, FileSystemError, Result};
 use crate::file_system::model::{MediaItemMetadata};
+use crate::file_system::raw;
+use crate::file_system::similarity::ImageSignature;
 
 #[derive(Clone)]
 pub struct Thumbnails(Arc<RwLock<ThumbnailsInternal>>);
 
 struct ThumbnailsInternal {
     cache_dir : PathBuf,
-    cache : HashMap<u64, PathBuf>
+    cache : HashMap<u64, PathBuf>,
+    signatures : HashMap<u64, ImageSignature>,
+    previews_dir : PathBuf,
+    previews : HashMap<u64, Vec<PathBuf>>,
+    thumbnail_size : u32,
+    preview_max_size : u32,
 }
 
+const DEFAULT_PREVIEW_QUALITY: u8 = 85;
+
+/// Requested sizes and qualities are rounded up to these, so clients can't fill the cache with
+/// variants which barely differ.
+const PREVIEW_SIZES: [u32; 6] = [256, 512, 1024, 1536, 2048, 4096];
+const PREVIEW_QUALITIES: [u8; 3] = [60, 85, 95];
+
+/// Older variants of an item are evicted beyond this
+const MAX_PREVIEWS_PER_ITEM: usize = 8;
+
 impl Thumbnails {
-    pub fn new(img_base_path : &Path) -> Self {
-        let mut cache_dir = img_base_path.to_path_buf();
-        cache_dir.push(".thumbnails");
-        println!("The thumbnail cache directory is {:?}", cache_dir);
+    pub fn new(cache_dir : &Path, thumbnail_size : u32, preview_max_size : u32) -> Result<Self> {
+        let cache_dir = cache_dir.to_path_buf();
+        info!("The thumbnail cache directory is {:?}", cache_dir);
         if !cache_dir.exists() {
-            std::fs::create_dir_all(&cache_dir).expect("Failed to create thumbnail cache dir!");
-            println!("The directory was created");
+            std::fs::create_dir_all(&cache_dir)?;
+            debug!("The directory was created");
         }
-        Thumbnails(Arc::new(RwLock::new(ThumbnailsInternal {
+        let cache = ThumbnailsInternal::restore_cache(&cache_dir)?;
+        info!("Restored {} thumbnails from the cache directory", cache.len());
+        let signatures = ThumbnailsInternal::restore_signatures(&cache);
+
+        let previews_dir = cache_dir.join("previews");
+        std::fs::create_dir_all(&previews_dir)?;
+        let previews = ThumbnailsInternal::restore_previews(&previews_dir)?;
+
+        Ok(Thumbnails(Arc::new(RwLock::new(ThumbnailsInternal {
             cache_dir,
-            cache: HashMap::new()
-        })))
+            cache,
+            signatures,
+            previews_dir,
+            previews,
+            thumbnail_size,
+            preview_max_size,
+        }))))
+    }
+
+    pub async fn cache_dir(&self) -> PathBuf {
+        self.0.read().await.cache_dir.clone()
     }
 
+    /// Renders the thumbnail without holding the lock, so thumbnails of other items can be read meanwhile.
     pub async fn load(&self, item : &MediaItemMetadata) -> Result<()> {
-        self.0.write().await.load(item).await
+        let (target_path, size) = {
+            let inner = self.0.read().await;
+            (inner.cache_dir.join(format!("{}.jpg", item.id)), inner.thumbnail_size)
+        };
+
+        debug!("Generating thumbnail for file {:?} into new file {:?}", item.path, target_path);
+
+        let (source, path) = (item.clone(), target_path.clone());
+        let signature = unblock(move || {
+            let thumbnail = open_image(&source)?.thumbnail(size, size);
+            thumbnail.save_with_format(&path, ImageFormat::Jpeg)?;
+            Ok(ImageSignature::of(&thumbnail))
+        }).await?;
+
+        self.0.write().await.insert(item.id, target_path, signature);
+
+        debug!("Thumbnail was generated");
+
+        Ok(())
     }
 
     pub async fn get(&self, id: &u64) -> Result<Vec<u8>> {
-        self.0.read().await.get(id).await
+        debug!("Requesting thumbnail {} from cache", id);
+        let path = self.0.read().await.cache.get(id).cloned();
+        match path {
+            Some(path) => {
+                debug!("Reading in thumbnail from path {:?}", path);
+                Ok(tokio::fs::read(path).await?)
+            }
+            None => Err(FileSystemError::UnknownId(*id))
+        }
     }
 
-    pub async fn remove(&self, id: &u64) -> Result<()> {
-        self.0.write().await.remove(id).await
-    }
-}
+    /// Renders the item to fit into `width` x `height`, both rounded up to the next preview size and
+    /// bounded by the configured maximum. The latest few variants are cached until the item leaves the
+    /// inbox or its content changes.
+    pub async fn preview(&self, item: &MediaItemMetadata, width: Option<u32>, height: Option<u32>, quality: Option<u8>) -> Result<Vec<u8>> {
+        let (target_path, width, height, quality) = {
+            let inner = self.0.read().await;
+            let width = snap_size(width.unwrap_or(inner.preview_max_size), inner.preview_max_size);
+            let height = snap_size(height.unwrap_or(inner.preview_max_size), inner.preview_max_size);
+            let quality = snap_quality(quality.unwrap_or(DEFAULT_PREVIEW_QUALITY));
+            let target_path = inner.previews_dir.join(format!("{}_{}x{}_q{}.jpg", item.id, width, height, quality));
+            (target_path, width, height, quality)
+        };
 
-impl ThumbnailsInternal {
+        match tokio::fs::read(&target_path).await {
+            Ok(data) => {
+                debug!("Read in cached preview from path {:?}", target_path);
+                return Ok(data);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into())
+        }
 
-    async fn load(&mut self, item : &MediaItemMetadata) -> Result<()> {
-        let target_name = format!("{}.jpg", item.id);
-        let mut target_path = self.cache_dir.clone();
-        target_path.push(target_name);
+        // rendering takes a while, so it happens without holding the lock
+        debug!("Rendering preview of {:?} into {:?}", item.path, target_path);
+        let (source, path) = (item.clone(), target_path.clone());
+        let data = unblock(move || {
+            let image = open_image(&source)?;
+            let image = if image.width() > width || image.height() > height {
+                image.resize(width, height, FilterType::Lanczos3)
+            } else {
+                image
+            };
 
-        println!("Generating thumbnail for file {:?} into new file {:?}", item.path, target_path);
+            let mut data = Vec::<u8>::new();
+            image.write_to(&mut data, ImageOutputFormat::Jpeg(quality))?;
+            std::fs::write(&path, &data)?;
+            Ok(data)
+        }).await?;
 
-        if let Some(_) = self.cache.insert(item.id, target_path.clone()) {
-            assert!(true, "Found a already used ID!")
+        let evicted = {
+            let mut inner = self.0.write().await;
+            let variants = inner.previews.entry(item.id).or_default();
+            if !variants.contains(&target_path) {
+                variants.push(target_path);
+            }
+            let excess = variants.len().saturating_sub(MAX_PREVIEWS_PER_ITEM);
+            variants.drain(..excess).collect::<Vec<PathBuf>>()
+        };
+        if !evicted.is_empty() {
+            debug!("Evicting previews {:?}", evicted);
+            unblock(move || {
+                for path in evicted.iter().filter(|path| path.exists()) {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(())
+            }).await?;
         }
+        Ok(data)
+    }
 
-        image::open(item.path.as_path())?
-            .thumbnail(512, 512)
-            .save_with_format(&target_path, ImageFormat::Jpeg)?;
-
-        println!("Thumbnail was generated");
+    pub async fn remove(&self, id: &u64) -> Result<()> {
+        let (thumbnail, previews) = self.0.write().await.forget(id);
+        let known = thumbnail.is_some();
+        unblock(move || {
+            for path in previews.iter().chain(thumbnail.iter()) {
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            Ok(())
+        }).await?;
 
-        Ok(())
+        if known {
+            debug!("Deleted thumbnail for id {}", id);
+            Ok(())
+        } else {
+            Err(FileSystemError::UnknownId(*id))
+        }
     }
 
-    async fn get(&self, id : &u64) -> Result<Vec<u8>> {
-        println!("Requesting thumbnail {} from cache", id);
-        if let Some(path) = self.cache.get(id) {
-            println!("Reading in thumbnail from path {:?}", path);
+    /// Signatures of all items with a thumbnail, keyed by item id.
+    pub async fn signatures(&self) -> HashMap<u64, ImageSignature> {
+        self.0.read().await.signatures.clone()
+    }
 
-            let data = std::fs::read(path)?;
+    pub async fn contains(&self, id: &u64) -> bool {
+        self.0.read().await.cache.contains_key(id)
+    }
 
-            Ok(data)
-        }else{
-            Err(FileSystemError::UnknownId(id.clone()))
+    /// Drops every thumbnail whose id is not in `known_ids`, e.g. because the item vanished while the server was down.
+    pub async fn retain(&self, known_ids: &[u64]) -> Result<()> {
+        let stale = {
+            let inner = self.0.read().await;
+            inner.cache.keys()
+                .chain(inner.previews.keys())
+                .filter(|id| !known_ids.contains(id))
+                .copied()
+                .collect::<HashSet<u64>>()
+        };
+        for id in stale {
+            info!("Dropping stale thumbnail for id {}", id);
+            if let Err(e) = self.remove(&id).await {
+                warn!("Dropping stale thumbnail {} failed: {:?}", id, e);
+            }
         }
+        Ok(())
     }
+}
 
-    async fn remove(&mut self, id : &u64) -> Result<()> {
-        if let Some(path) = self.cache.get(&id) {
-            std::fs::remove_file(path)?;
+impl ThumbnailsInternal {
+
+    fn restore_cache(cache_dir : &Path) -> Result<HashMap<u64, PathBuf>> {
+        let mut cache = HashMap::new();
+        for entry in cache_dir.read_dir()?.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                continue;
+            }
+            let id = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+            match id {
+                Some(id) => {
+                    cache.insert(id, path);
+                }
+                _ => warn!("Ignoring unexpected entry {:?} in thumbnail cache", path)
+            }
+        }
+        Ok(cache)
+    }
 
-            self.cache.remove(&id).expect("Failed to remove from cache HashMap though id is known!");
+    /// Signatures aren't persisted; they are cheap to derive from the thumbnails again.
+    fn restore_signatures(cache : &HashMap<u64, PathBuf>) -> HashMap<u64, ImageSignature> {
+        let mut signatures = HashMap::new();
+        for (id, path) in cache.iter() {
+            match image::open(path) {
+                Ok(thumbnail) => {
+                    signatures.insert(*id, ImageSignature::of(&thumbnail));
+                }
+                Err(e) => warn!("Reading thumbnail {:?} for its signature failed: {:?}", path, e)
+            }
+        }
+        signatures
+    }
 
-            println!("Deleted thumbnail for id {}", id);
+    fn restore_previews(previews_dir : &Path) -> Result<HashMap<u64, Vec<PathBuf>>> {
+        let mut previews = HashMap::<u64, Vec<PathBuf>>::new();
+        for entry in previews_dir.read_dir()?.flatten() {
+            let path = entry.path();
+            // previews are named <id>_<width>x<height>_q<quality>.jpg
+            let id = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split('_').next())
+                .and_then(|id| id.parse::<u64>().ok());
+            match id {
+                Some(id) if path.is_file() => previews.entry(id).or_default().push(path),
+                _ => warn!("Ignoring unexpected entry {:?} in preview cache", path)
+            }
+        }
+        Ok(previews)
+    }
 
-            Ok(())
-        }else{
-            Err(FileSystemError::UnknownId(id.clone()))
+    fn insert(&mut self, id : u64, path : PathBuf, signature : ImageSignature) {
+        self.signatures.insert(id, signature);
+        if self.cache.insert(id, path).is_some() {
+            debug!("Replaced an existing thumbnail for id {}", id)
         }
     }
 
+    /// Forgets the item's thumbnail and previews and returns their paths for deletion.
+    fn forget(&mut self, id : &u64) -> (Option<PathBuf>, Vec<PathBuf>) {
+        self.signatures.remove(id);
+        (self.cache.remove(id), self.previews.remove(id).unwrap_or_default())
+    }
+
+}
+
+/// The smallest preview size fitting `requested`, at most `max`.
+fn snap_size(requested : u32, max : u32) -> u32 {
+    PREVIEW_SIZES.iter().copied()
+        .find(|size| *size >= requested)
+        .unwrap_or(max)
+        .min(max)
 }
 
+fn snap_quality(requested : u8) -> u8 {
+    PREVIEW_QUALITIES.iter().copied()
+        .find(|quality| *quality >= requested)
+        .unwrap_or(PREVIEW_QUALITIES[PREVIEW_QUALITIES.len() - 1])
+}
+
+fn open_image(item : &MediaItemMetadata) -> Result<DynamicImage> {
+    if raw::is_raw(&item.mime) {
+        raw::load_embedded_preview(item.path.as_path())
+    } else {
+        Ok(image::open(item.path.as_path())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preview_sizes_round_up_to_the_next_bucket() {
+        assert_eq!(snap_size(1, 2048), 256);
+        assert_eq!(snap_size(256, 2048), 256);
+        assert_eq!(snap_size(257, 2048), 512);
+        assert_eq!(snap_size(1500, 2048), 1536);
+        assert_eq!(snap_size(1500, 1200), 1200);
+        assert_eq!(snap_size(10_000, 8000), 8000);
+    }
+
+    #[test]
+    fn preview_qualities_round_up_to_the_next_level() {
+        assert_eq!(snap_quality(1), 60);
+        assert_eq!(snap_quality(85), 85);
+        assert_eq!(snap_quality(86), 95);
+        assert_eq!(snap_quality(100), 95);
+    }
+}
@@ -0,0 +1,288 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sha2::{Digest, Sha256};
+use log::{debug, info, warn};
+
+use crate::file_system::{FileSystemError, Result};
+use crate::file_system::destinations::CollisionStrategy;
+use crate::file_system::model::ItemOutcome;
+
+/// How `place` dealt with the file.
+pub enum Placement {
+    /// The file was moved to the given path; the checksum is only computed when verifying
+    Placed(PathBuf, ItemOutcome, Option<String>),
+    /// The destination path is taken and the strategy forbids touching it
+    Conflict(PathBuf),
+    /// The destination path is taken and the file stays where it is
+    Skipped,
+    /// An identical file already is at the given path; the source is left in place
+    Identical(PathBuf),
+}
+
+/// Moves `src` to `destination_path`, resolving an existing file there according to `strategy`.
+/// With `verify`, a copy is compared to its source before the source is deleted.
+pub fn place(src: &Path, destination_path: PathBuf, strategy: CollisionStrategy, verify: bool) -> Result<Placement> {
+    if !src.is_file() {
+        return Err(FileSystemError::FileNotFound(src.to_path_buf()));
+    }
+
+    let (dst, outcome) = if destination_path.exists() {
+        info!("'{:?}' already exists; Resolving with {:?}", destination_path, strategy);
+        match strategy {
+            CollisionStrategy::Fail => return Ok(Placement::Conflict(destination_path)),
+            CollisionStrategy::Skip => return Ok(Placement::Skipped),
+            CollisionStrategy::Overwrite => (destination_path, ItemOutcome::Overwritten),
+            CollisionStrategy::Suffix => (free_suffixed_path(&destination_path), ItemOutcome::Renamed),
+            CollisionStrategy::Deduplicate => {
+                if files_identical(src, &destination_path)? {
+                    return Ok(Placement::Identical(destination_path));
+                }
+                (free_suffixed_path(&destination_path), ItemOutcome::Renamed)
+            }
+        }
+    } else {
+        (destination_path, ItemOutcome::Moved)
+    };
+
+    info!("Moving '{:?}' to '{:?}'", src, dst);
+
+    match dst.parent() {
+        Some(parent_dir) => {
+            if !parent_dir.exists() {
+                debug!("Missing destination directory; Creating");
+                std::fs::create_dir_all(parent_dir)?
+            }
+        }
+        None => return Err(FileSystemError::NoParentDirectory(dst))
+    }
+
+    let checksum = relocate(src, &dst, verify)?;
+    Ok(Placement::Placed(dst, outcome, checksum))
+}
+
+/// Moves `src` to `dst`, falling back to copying and deleting when they are on different devices.
+pub fn move_file(src: &Path, dst: &Path) -> Result<()> {
+    if dst.exists() {
+        return Err(FileSystemError::DestinationExists(format!("Can't move '{:?}' to '{:?}'; the destination exists", src, dst)));
+    }
+
+    match dst.parent() {
+        Some(parent_dir) => {
+            if !parent_dir.exists() {
+                std::fs::create_dir_all(parent_dir)?
+            }
+        }
+        None => return Err(FileSystemError::NoParentDirectory(dst.to_path_buf()))
+    }
+
+    relocate(src, dst, false)?;
+    Ok(())
+}
+
+/// Renames `src` to `dst`, which replaces a file at `dst`. Across devices the file is copied
+/// atomically instead and the source is deleted only once the copy is safely on disk.
+/// With `verify`, the checksum of the moved file is returned.
+fn relocate(src: &Path, dst: &Path, verify: bool) -> Result<Option<String>> {
+    match std::fs::rename(src, dst) {
+        // a rename doesn't touch the content, so there is no copy to compare
+        Ok(_) if verify => Ok(Some(content_hash(dst)?)),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            let checksum = copy_atomically(src, dst, verify)?;
+            std::fs::remove_file(src)?;
+            Ok(checksum)
+        }
+        Err(e) => Err(e.into())
+    }
+}
+
+/// Copies `src` to a temporary file next to `dst`, flushes it to disk and renames it into place,
+/// so `dst` never holds a partial file, even if the process dies midway. With `verify`, the copy
+/// only is renamed into place if its checksum matches the source's, which then is returned.
+fn copy_atomically(src: &Path, dst: &Path, verify: bool) -> Result<Option<String>> {
+    let tmp = temporary_path(dst);
+    let copied = std::fs::copy(src, &tmp)
+        .and_then(|_| File::open(&tmp)?.sync_all())
+        .map_err(FileSystemError::from)
+        .and_then(|_| if verify { verified_checksum(src, &tmp).map(Some) } else { Ok(None) })
+        .and_then(|checksum| {
+            std::fs::rename(&tmp, dst)?;
+            Ok(checksum)
+        });
+    let checksum = match copied {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            if tmp.exists() {
+                let _ = std::fs::remove_file(&tmp);
+            }
+            return Err(e);
+        }
+    };
+
+    // the rename only is durable once the directory is flushed as well
+    if let Some(parent_dir) = dst.parent() {
+        File::open(parent_dir)?.sync_all()?;
+    }
+    Ok(checksum)
+}
+
+/// Hashes both files and returns the checksum if they match.
+fn verified_checksum(src: &Path, copy: &Path) -> Result<String> {
+    let expected = content_hash(src)?;
+    let actual = content_hash(copy)?;
+    if expected == actual {
+        debug!("Verified the copy of '{:?}' with checksum {}", src, actual);
+        Ok(actual)
+    } else {
+        warn!("The copy of '{:?}' has checksum {} instead of {}; Keeping the source", src, actual, expected);
+        Err(FileSystemError::ChecksumMismatch(src.to_path_buf()))
+    }
+}
+
+const TEMPORARY_SUFFIX: &str = ".filebase-partial";
+
+fn temporary_path(dst: &Path) -> PathBuf {
+    let name = dst.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    dst.with_file_name(format!(".{}{}", name, TEMPORARY_SUFFIX))
+}
+
+/// Tells concurrent write probes apart, e.g. of destinations sharing a base path
+static PROBE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// How many taken probe names are skipped before giving up
+const PROBE_ATTEMPTS: usize = 16;
+
+fn probe_path(dir: &Path, n: u64) -> PathBuf {
+    temporary_path(&dir.join(format!("write-test-{}-{}", std::process::id(), n)))
+}
+
+/// Fails unless `dir` is a directory files can be created in.
+pub fn check_writable(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(FileSystemError::FileNotFound(dir.to_path_buf()));
+    }
+    // permissions alone don't tell, e.g. for read-only mounts
+    for _ in 0..PROBE_ATTEMPTS {
+        let probe = probe_path(dir, PROBE_COUNTER.fetch_add(1, Ordering::Relaxed));
+        let mut file = match std::fs::OpenOptions::new().write(true).create_new(true).open(&probe) {
+            // left behind by a crash; the next name will do
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            opened => opened?
+        };
+        let written = file.write_all(b"filebase").and_then(|_| file.sync_all());
+        drop(file);
+        if let Err(e) = std::fs::remove_file(&probe) {
+            warn!("Failed to remove the write probe {:?}: {}", probe, e);
+        }
+        return written.map_err(FileSystemError::from);
+    }
+    Err(FileSystemError::IOError(format!("Every write probe name in {:?} is taken", dir)))
+}
+
+/// Whether the path is an unfinished copy, e.g. one left behind by a crash.
+pub fn is_temporary(path: &Path) -> bool {
+    path.file_name()
+        .map(|n| n.to_string_lossy().ends_with(TEMPORARY_SUFFIX))
+        .unwrap_or(false)
+}
+
+/// Compares both files byte by byte.
+pub fn files_identical(a: &Path, b: &Path) -> Result<bool> {
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut reader_a = BufReader::new(File::open(a)?);
+    let mut reader_b = BufReader::new(File::open(b)?);
+    let mut buf_a = [0u8; 64 * 1024];
+    let mut buf_b = [0u8; 64 * 1024];
+    loop {
+        let read = reader_a.read(&mut buf_a)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        reader_b.read_exact(&mut buf_b[..read])?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of the file's content.
+pub fn content_hash(path: &Path) -> std::io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            return Ok(format!("{:x}", hasher.finalize()));
+        }
+        hasher.update(&buf[..read]);
+    }
+}
+
+/// Finds the first of `name_1.ext`, `name_2.ext`, ... next to `path` which doesn't exist yet.
+pub fn free_suffixed_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    let mut suffix = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{}_{}{}", stem, suffix, extension));
+        if !candidate.exists() {
+            return candidate;
+        }
+        suffix += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_skip_stale_probe_files() {
+        let dir = std::env::temp_dir().join(format!("filebase-probe-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // the files a crashed probe of this process would have left behind
+        let next = PROBE_COUNTER.load(Ordering::Relaxed);
+        let stale = (next..next + 3).map(|n| probe_path(&dir, n)).collect::<Vec<PathBuf>>();
+        for path in stale.iter() {
+            File::create(path).unwrap();
+        }
+
+        check_writable(&dir).unwrap();
+        check_writable(&dir).unwrap();
+
+        let mut left = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<PathBuf>>();
+        left.sort();
+        let mut expected = stale.clone();
+        expected.sort();
+        assert_eq!(left, expected);
+        assert!(stale.iter().all(|path| is_temporary(path)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
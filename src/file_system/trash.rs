@@ -0,0 +1,178 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use log::{info, warn};
+
+use crate::file_system::{unblock, FileSystemError, Result};
+use crate::file_system::append_log::AppendLog;
+use crate::file_system::model::{MediaItemMetadata, TrashedMediaItem};
+use crate::file_system::transfer;
+
+type Dt = chrono::DateTime<chrono::Utc>;
+
+const INDEX_FILE: &str = "index.jsonl";
+
+#[derive(Clone)]
+pub struct Trash(Arc<RwLock<TrashInternal>>);
+
+#[derive(Serialize, Deserialize, Debug)]
+enum TrashRecord {
+    /// The trashed item, its original path and the path of the file inside the trash
+    Trashed(Box<TrashedMediaItem>, PathBuf, PathBuf),
+    Removed(u64),
+}
+
+struct TrashInternal {
+    trash_dir: PathBuf,
+    items: HashMap<u64, TrashedMediaItem>,
+    log: AppendLog<TrashRecord>,
+}
+
+impl Trash {
+    pub fn open(trash_dir: &Path) -> Result<Self> {
+        Ok(Trash(Arc::new(RwLock::new(TrashInternal::open(trash_dir)?))))
+    }
+
+    /// Moves the item into the trash and returns where it is kept.
+    pub async fn put(&self, item: &MediaItemMetadata) -> Result<PathBuf> {
+        let trash_path = self.0.read().await.trash_dir.join(format!("{}_{}", item.id, item.name));
+        info!("Moving '{:?}' to the trash at '{:?}'", item.path, trash_path);
+        let (src, dst) = (item.path.clone(), trash_path.clone());
+        unblock(move || transfer::move_file(&src, &dst)).await?;
+        self.0.write().await.record(item, trash_path).await
+    }
+
+    pub async fn list(&self) -> Vec<TrashedMediaItem> {
+        self.0.read().await.items.values().cloned().collect::<Vec<TrashedMediaItem>>()
+    }
+
+    /// Moves the item back to its original location and returns its metadata.
+    pub async fn restore(&self, id: &u64) -> Result<MediaItemMetadata> {
+        let trashed = self.0.read().await.get(id)?;
+        info!("Restoring '{:?}' from the trash", trashed.item.path);
+        let (src, dst) = (trashed.trash_path.clone(), trashed.item.path.clone());
+        unblock(move || transfer::move_file(&src, &dst)).await?;
+        self.0.write().await.forget(id).await?;
+        Ok(trashed.item)
+    }
+
+    pub async fn purge(&self, id: &u64) -> Result<()> {
+        let trashed = self.0.read().await.get(id)?;
+        info!("Purging '{:?}' from the trash", trashed.trash_path);
+        unblock(move || {
+            if trashed.trash_path.exists() {
+                std::fs::remove_file(&trashed.trash_path)?;
+            }
+            Ok(())
+        }).await?;
+        self.0.write().await.forget(id).await
+    }
+
+    pub async fn purge_trashed_before(&self, threshold: Dt) -> Result<()> {
+        let expired = self.0.read().await.items.values()
+            .filter(|t| t.trashed_on < threshold)
+            .map(|t| t.item.id)
+            .collect::<Vec<u64>>();
+
+        let mut failures = Vec::<FileSystemError>::new();
+        for id in expired {
+            if let Err(e) = self.purge(&id).await {
+                failures.push(e)
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(FileSystemError::MultipleErrors(failures))
+        }
+    }
+}
+
+impl TrashInternal {
+    fn open(trash_dir: &Path) -> Result<Self> {
+        info!("The trash directory is {:?}", trash_dir);
+        std::fs::create_dir_all(trash_dir)?;
+
+        let log_path = trash_dir.join(INDEX_FILE);
+        let mut items = HashMap::new();
+        for record in AppendLog::<TrashRecord>::replay(&log_path)? {
+            match record {
+                TrashRecord::Trashed(mut trashed, original_path, trash_path) => {
+                    trashed.item.path = original_path;
+                    trashed.trash_path = trash_path;
+                    items.insert(trashed.item.id, *trashed);
+                }
+                TrashRecord::Removed(id) => {
+                    items.remove(&id);
+                }
+            }
+        }
+
+        items.retain(|id, trashed: &mut TrashedMediaItem| {
+            let exists = trashed.trash_path.is_file();
+            if !exists {
+                warn!("Trashed item {} is missing from the trash directory; Forgetting it", id);
+            }
+            exists
+        });
+
+        let records = items.values()
+            .map(|t| TrashRecord::Trashed(Box::new(t.clone()), t.item.path.clone(), t.trash_path.clone()))
+            .collect::<Vec<TrashRecord>>();
+        let log = AppendLog::rewrite(&log_path, &records)?;
+        info!("Restored {} items from the trash", items.len());
+
+        Ok(TrashInternal {
+            trash_dir: trash_dir.to_path_buf(),
+            items,
+            log,
+        })
+    }
+
+    async fn record(&mut self, item: &MediaItemMetadata, trash_path: PathBuf) -> Result<PathBuf> {
+        let trashed = TrashedMediaItem {
+            item: item.clone(),
+            trashed_on: chrono::Utc::now(),
+            trash_path,
+        };
+        self.log.append(&TrashRecord::Trashed(Box::new(trashed.clone()), trashed.item.path.clone(), trashed.trash_path.clone())).await?;
+        let trash_path = trashed.trash_path.clone();
+        self.items.insert(item.id, trashed);
+        Ok(trash_path)
+    }
+
+    fn get(&self, id: &u64) -> Result<TrashedMediaItem> {
+        match self.items.get(id) {
+            Some(trashed) => Ok(trashed.clone()),
+            None => Err(FileSystemError::UnknownId(*id))
+        }
+    }
+
+    async fn forget(&mut self, id: &u64) -> Result<()> {
+        match self.items.remove(id) {
+            Some(_) => self.log.append(&TrashRecord::Removed(*id)).await,
+            None => Err(FileSystemError::UnknownId(*id))
+        }
+    }
+}
@@ -19,10 +19,14 @@ use std::path::{Path, PathBuf};
 use std::sync::mpsc::channel;
 use std::time::Duration;
 
-use new_mime_guess::MimeGuess;
 use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use log::{debug, info, warn};
 
+use crate::file_system::events::{EventBus, InboxEvent};
+use crate::file_system::model::MediaItemMetadata;
+use crate::file_system::raw;
 use crate::file_system::storage::MediaItemMetadataStorage;
+use crate::file_system::transfer;
 use crate::file_system::FileSystemError;
 use std::thread;
 use chrono::TimeZone;
@@ -51,26 +55,46 @@ struct FileSystemWatchdog(
     tokio::runtime::Runtime,
 );
 
+#[derive(Default)]
+struct ExifDetails {
+    taken_on: Option<chrono::DateTime<chrono::Utc>>,
+    camera_make: Option<String>,
+    camera_model: Option<String>,
+}
+
 struct FileSystemWatchdogData {
+    inbox: String,
     monitoring_dir: PathBuf,
+    excluded_dirs: Vec<PathBuf>,
     storage: MediaItemMetadataStorage,
-    thumbnails : Thumbnails
+    thumbnails : Thumbnails,
+    events: EventBus,
 }
 
 impl FileSystemWatchdogBuilder {
-    pub fn new(monitoring: &Path, storage: MediaItemMetadataStorage, thumbnails : Thumbnails) -> Self {
+    /// Watches `monitoring` for items of the inbox named `inbox`.
+    pub fn new(inbox: &str, monitoring: &Path, storage: MediaItemMetadataStorage, thumbnails : Thumbnails, events: EventBus) -> Self {
         FileSystemWatchdogBuilder(FileSystemWatchdogData {
+            inbox: inbox.to_string(),
             monitoring_dir: monitoring.to_path_buf(),
+            excluded_dirs: Vec::new(),
             storage,
-            thumbnails
+            thumbnails,
+            events,
         })
     }
 
+    /// Ignores everything below `dir`, e.g. caches which live inside the monitoring dir.
+    pub fn exclude(mut self, dir: &Path) -> Self {
+        self.0.excluded_dirs.push(dir.to_path_buf());
+        self
+    }
+
     pub fn launch(self) -> std::thread::JoinHandle<Result<()>> {
         let data = self.0;
 
         thread::spawn(move || {
-            println!("Launching Watchdog");
+            info!("Launching Watchdog for inbox '{}'", data.inbox);
             let rt = tokio::runtime::Runtime::new().expect("Failed to spawn new runtime in watchdog thread!");
             let wd = FileSystemWatchdog::new(data, rt);
             wd.watch()
@@ -84,8 +108,8 @@ impl FileSystemWatchdog {
     }
 
     fn watch(self) -> Result<()> {
-        println!("Scanning monitoring dir for existing files");
-        self.scan_directory();
+        info!("Reconciling storage with the monitoring dir");
+        self.reconcile()?;
 
         let (tx, rx) = channel();
 
@@ -94,18 +118,18 @@ impl FileSystemWatchdog {
             Err(e) => return Err(FilesystemWatchdogError::WatchdogError(e.to_string()))
         };
 
-        if let Err(e) = watchdog.watch(self.0.monitoring_dir.to_path_buf(), RecursiveMode::NonRecursive) {
+        if let Err(e) = watchdog.watch(&self.0.monitoring_dir, RecursiveMode::Recursive) {
             return Err(FilesystemWatchdogError::WatchdogError(e.to_string()));
         }
 
-        println!("Starting to watch for events on {:?}", self.0.monitoring_dir);
+        info!("Starting to watch for events on {:?}", self.0.monitoring_dir);
 
         loop {
             match rx.recv() {
                 Ok(event) => {
                     match self.handle_event(event) {
                         Ok(_) => {}
-                        Err(e) => println!("{:?}", e)
+                        Err(e) => warn!("{:?}", e)
                     }
                 }
                 Err(err) => return Err(FilesystemWatchdogError::ChannelError(err.to_string()))
@@ -116,121 +140,365 @@ impl FileSystemWatchdog {
     fn handle_event(&self, event: DebouncedEvent) -> Result<()> {
         match event {
             DebouncedEvent::NoticeWrite(pb) | DebouncedEvent::Write(pb) | DebouncedEvent::Create(pb) => {
-                if !self.block_on(self.0.storage.is_path_known(&pb)) && pb.is_file() {
-                    self.store_new_file(pb)
-                } else {
+                if self.is_excluded(&pb) {
+                    Ok(())
+                } else if pb.is_dir() {
+                    // files moved in together with their folder don't raise events of their own
+                    self.scan_directory(&pb);
+                    Ok(())
+                } else if !pb.is_file() {
                     Ok(())
+                } else if self.block_on(self.0.storage.is_path_known(&pb)) {
+                    // the file may still have been written to after it was first picked up
+                    self.refresh_content_hash(&pb)
+                } else {
+                    self.store_new_file(pb)
                 }
             }
-            DebouncedEvent::NoticeRemove(pb) | DebouncedEvent::Remove(pb) => {
-                self.block_on(self.0.storage.remove_if_known(&pb));
+            DebouncedEvent::NoticeRemove(_) => {
+                // renames also start with this notice; the actual Remove or Rename follows after debouncing
                 Ok(())
             }
+            DebouncedEvent::Remove(pb) => {
+                if self.is_excluded(&pb) {
+                    Ok(())
+                } else {
+                    self.forget_path(&pb)
+                }
+            }
             DebouncedEvent::Chmod(_) => {
-                println!("Watchdog: chmod");
+                debug!("Watchdog: chmod");
                 Ok(())
             }
-            DebouncedEvent::Rename(_src, _dst) => panic!("Filesystem rename is not supported!"),
-            DebouncedEvent::Rescan => panic!("Filesystem Rescan is not supported!"),
+            DebouncedEvent::Rename(src, dst) => self.handle_rename(&src, &dst),
+            DebouncedEvent::Rescan => {
+                info!("Watchdog: rescan requested; Reconciling storage with the monitoring dir");
+                self.reconcile()
+            }
             DebouncedEvent::Error(err, _opt_pb) => {
                 Err(FilesystemWatchdogError::WatchdogError(err.to_string()))
             }
         }
     }
 
-    fn scan_directory(&self) {
-        let dts = &self.0.monitoring_dir;
-        for fs_entry in dts.read_dir().expect("Read dir failed!") {
-            if let Ok(entry) = fs_entry {
-                if entry.path().is_file() && !self.block_on(self.0.storage.is_path_known(entry.path().as_path())) {
-                    self.store_new_file(entry.path()).expect("Error while storing newly found file!");
+    fn handle_rename(&self, src: &Path, dst: &Path) -> Result<()> {
+        let dst_watched = dst.starts_with(&self.0.monitoring_dir) && !self.is_excluded(dst);
+        if !dst_watched {
+            info!("{:?} was moved out of the monitoring dir to {:?}", src, dst);
+            return self.forget_path(src);
+        }
+
+        let affected = self.block_on(self.0.storage.list_below(src));
+
+        if affected.is_empty() {
+            // nothing known was renamed, e.g. a file moved in from an excluded dir; treat it like a new arrival
+            if dst.is_dir() {
+                self.scan_directory(dst);
+            } else if dst.is_file() && !self.block_on(self.0.storage.is_path_known(dst)) {
+                self.store_new_file(dst.to_path_buf())?;
+            }
+            return Ok(());
+        }
+
+        for item in affected {
+            let relative = item.path.strip_prefix(src).expect("Affected item is not below the renamed path!");
+            let new_path = if relative.as_os_str().is_empty() {
+                dst.to_path_buf()
+            } else {
+                dst.join(relative)
+            };
+            self.relocate_file(item, new_path)?;
+        }
+        Ok(())
+    }
+
+    /// Points a stored item at its new path while keeping its id. Sync clients commonly rename a
+    /// temporary file into place, so the metadata and thumbnail are refreshed when the type changes.
+    fn relocate_file(&self, mut item: MediaItemMetadata, new_path: PathBuf) -> Result<()> {
+        info!("Item {} was renamed from {:?} to {:?}", item.id, item.path, new_path);
+        if self.block_on(self.0.storage.is_path_known(&new_path)) {
+            // the rename replaced another known file
+            self.forget_path(&new_path)?;
+        }
+        let description = self.describe_file(new_path.as_path())?;
+        let mime_changed = description.mime != item.mime;
+
+        item.path = new_path;
+        item.name = description.name;
+        item.inbox = description.inbox;
+        item.subfolder = description.subfolder;
+        let content_changed = item.content_hash != description.content_hash;
+        item.content_hash = description.content_hash;
+        if mime_changed {
+            item.mime = description.mime;
+            item.creation_date = description.creation_date;
+            item.camera_make = description.camera_make;
+            item.camera_model = description.camera_model;
+        }
+
+        self.block_on(self.0.storage.update(item.clone()))
+            .map_err(FilesystemWatchdogError::StorageError)?;
+        self.0.events.publish(InboxEvent::ItemUpdated { item: item.clone() });
+
+        if mime_changed || content_changed || !self.block_on(self.0.thumbnails.contains(&item.id)) {
+            self.rerender_thumbnail(&item)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the cached thumbnail and previews of the item, which show its old content, and renders the thumbnail anew.
+    fn rerender_thumbnail(&self, item: &MediaItemMetadata) -> Result<()> {
+        match self.block_on(self.0.thumbnails.remove(&item.id)) {
+            Ok(()) | Err(FileSystemError::UnknownId(_)) => {}
+            Err(e) => return Err(FilesystemWatchdogError::ThumbnailError(e))
+        }
+        self.block_on(self.0.thumbnails.load(item))
+            .map_err(FilesystemWatchdogError::ThumbnailError)?;
+        self.0.events.publish(InboxEvent::ThumbnailReady { id: item.id });
+        Ok(())
+    }
+
+    /// Brings storage and thumbnails in line with what is actually on disk: items whose file vanished
+    /// are dropped, missing thumbnails are regenerated and files not yet known are added.
+    /// Items of other inboxes are left to their own watchdogs.
+    fn reconcile(&self) -> Result<()> {
+        let stored = self.block_on(self.0.storage.list_files())
+            .map_err(FilesystemWatchdogError::StorageError)?;
+
+        let mut known_ids = Vec::<u64>::new();
+        for mut item in stored.into_iter().filter(|item| item.path.starts_with(&self.0.monitoring_dir)) {
+            if item.path.is_file() {
+                known_ids.push(item.id);
+                if item.inbox != self.0.inbox || item.added_on.is_none() {
+                    // stored before inboxes were named or arrivals were recorded
+                    item.inbox = self.0.inbox.clone();
+                    item.added_on.get_or_insert_with(chrono::Utc::now);
+                    self.block_on(self.0.storage.update(item.clone()))
+                        .map_err(FilesystemWatchdogError::StorageError)?;
+                    self.0.events.publish(InboxEvent::ItemUpdated { item });
+                }
+                continue;
+            }
+            info!("Stored item {} at {:?} no longer exists; Removing", item.id, item.path);
+            self.block_on(self.0.storage.remove_file(&item.id))
+                .map_err(FilesystemWatchdogError::StorageError)?;
+            self.0.events.publish(InboxEvent::ItemRemoved { id: item.id });
+        }
+
+        self.block_on(self.0.thumbnails.retain(&known_ids))
+            .map_err(FilesystemWatchdogError::ThumbnailError)?;
+
+        for id in known_ids.iter() {
+            let item = self.block_on(self.0.storage.get_item(id))
+                .map_err(FilesystemWatchdogError::StorageError)?;
+            if item.content_hash.is_none() {
+                // stored before content hashes were introduced
+                self.refresh_content_hash(&item.path)?;
+            }
+            if self.block_on(self.0.thumbnails.contains(id)) {
+                continue;
+            }
+            match self.block_on(self.0.thumbnails.load(&item)) {
+                Ok(_) => self.0.events.publish(InboxEvent::ThumbnailReady { id: item.id }),
+                Err(e) => warn!("Regenerating thumbnail for {:?} failed: {:?}", item.path, e)
+            }
+        }
+
+        self.scan_directory(&self.0.monitoring_dir);
+        Ok(())
+    }
+
+    fn scan_directory(&self, dir: &Path) {
+        let entries = match dir.read_dir() {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Reading directory {:?} failed: {}", dir, e);
+                return;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if self.is_excluded(&path) {
+                continue;
+            }
+            if path.is_dir() {
+                self.scan_directory(&path);
+            } else if path.is_file() && !self.block_on(self.0.storage.is_path_known(&path)) {
+                if let Err(e) = self.store_new_file(path) {
+                    warn!("Error while storing newly found file: {:?}", e);
                 }
             }
         }
     }
 
+    /// Drops every stored item at or below `path`, which covers both removed files and removed folders.
+    fn forget_path(&self, path: &Path) -> Result<()> {
+        for item in self.block_on(self.0.storage.list_below(path)) {
+            self.block_on(self.0.storage.remove_file(&item.id))
+                .map_err(FilesystemWatchdogError::StorageError)?;
+            self.0.events.publish(InboxEvent::ItemRemoved { id: item.id });
+            if self.block_on(self.0.thumbnails.contains(&item.id)) {
+                self.block_on(self.0.thumbnails.remove(&item.id))
+                    .map_err(FilesystemWatchdogError::ThumbnailError)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn refresh_content_hash(&self, path: &Path) -> Result<()> {
+        let mut item = self.block_on(self.0.storage.get_item_by_path(path))
+            .map_err(FilesystemWatchdogError::StorageError)?;
+        let content_hash = Some(transfer::content_hash(path)?);
+        if item.content_hash != content_hash {
+            info!("Content of item {} at {:?} changed; Updating its hash", item.id, path);
+            // items stored before content hashes were introduced just get theirs filled in
+            let rerender = item.content_hash.is_some();
+            item.content_hash = content_hash;
+            self.block_on(self.0.storage.update(item.clone()))
+                .map_err(FilesystemWatchdogError::StorageError)?;
+            self.0.events.publish(InboxEvent::ItemUpdated { item: item.clone() });
+            if rerender {
+                self.rerender_thumbnail(&item)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        // unfinished copies only appear for a moment before they are renamed into place
+        self.0.excluded_dirs.iter().any(|dir| path.starts_with(dir)) || transfer::is_temporary(path)
+    }
+
     fn store_new_file(&self, path: PathBuf) -> Result<()> {
+        let description = self.describe_file(path.as_path())?;
+
+        info!("Adding file {:?}", path);
+
+        let r = self.block_on(
+            self.0.storage.add_file(description)
+        );
+
+        match r {
+            Ok(item) => {
+                self.0.events.publish(InboxEvent::ItemAdded { item: item.clone() });
+                let thumbnail_res = self.block_on(
+                    self.0.thumbnails.load(
+                        &item
+                    )
+                );
+
+                match thumbnail_res {
+                    Ok(_) => {
+                        self.0.events.publish(InboxEvent::ThumbnailReady { id: item.id });
+                        Ok(())
+                    },
+                    Err(e) => Err(FilesystemWatchdogError::ThumbnailError(e))
+                }
+            },
+            Err(e) => Err(FilesystemWatchdogError::StorageError(e))
+
+        }
+    }
+
+    /// Collects the metadata of the file at `path`; the id is only assigned once it is stored.
+    fn describe_file(&self, path: &Path) -> Result<MediaItemMetadata> {
         let fnm = path.file_name().expect("Given Path is no file!");
         if let Some(filename) = fnm.to_str() {
-            let mime = MimeGuess::from_path(&path).first_or_octet_stream();
+            let subfolder = match path.parent().and_then(|parent| parent.strip_prefix(&self.0.monitoring_dir).ok()) {
+                Some(relative) => match relative.to_str() {
+                    Some(relative) => relative.to_string(),
+                    None => return Err(FilesystemWatchdogError::NoUtf8Filename(relative.as_os_str().to_os_string()))
+                },
+                None => String::new()
+            };
+
+            let mime = raw::guess_mime(path);
             let mime_type = mime.to_string();
 
 
-            let creation_date = if mime.type_() == new_mime_guess::mime::IMAGE {
-                match self.read_date_taken_from_exif(path.as_path()) {
-                    Ok(date) => date,
+            let exif = if mime.type_() == new_mime_guess::mime::IMAGE {
+                match self.read_exif(path) {
+                    Ok(details) => details,
                     Err(e) => {
-                        println!("Reading EXIF data failed for reason '{:?}'; Falling back to file metadata.", e);
-                        let created = path.metadata()?.created()?;
-                        chrono::DateTime::<chrono::Utc>::from(created)
+                        debug!("Reading EXIF data failed for reason '{:?}'; Falling back to file metadata.", e);
+                        ExifDetails::default()
                     }
                 }
             }else{
-                let created = path.metadata()?.created()?;
-                chrono::DateTime::<chrono::Utc>::from(created)
+                ExifDetails::default()
             };
 
-            println!("Adding file {:?}", path);
-
-            let r = self.block_on(
-                self.0.storage.add_file(
-                    path.as_path(),
-                    String::from(filename),
-                    mime_type,
-                    creation_date)
-            );
-
-            match r {
-                Ok(item) => {
-                    let thumbnail_res = self.block_on(
-                        self.0.thumbnails.load(
-                            &item
-                        )
-                    );
-
-                    match thumbnail_res {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(FilesystemWatchdogError::ThumbnailError(e))
-                    }
-                },
-                Err(e) => Err(FilesystemWatchdogError::StorageError(e))
-
-            }
+            let creation_date = match exif.taken_on {
+                Some(taken_on) => taken_on,
+                None => {
+                    let created = path.metadata()?.created()?;
+                    chrono::DateTime::<chrono::Utc>::from(created)
+                }
+            };
 
+            Ok(MediaItemMetadata {
+                id: 0,
+                name: String::from(filename),
+                inbox: self.0.inbox.clone(),
+                subfolder,
+                mime: mime_type,
+                creation_date,
+                camera_make: exif.camera_make,
+                camera_model: exif.camera_model,
+                content_hash: Some(transfer::content_hash(path)?),
+                added_on: Some(chrono::Utc::now()),
+                path: path.to_path_buf(),
+            })
         } else {
             Err(FilesystemWatchdogError::NoUtf8Filename(fnm.to_os_string()))
         }
     }
 
-    fn read_date_taken_from_exif(&self, path : &Path) -> Result<chrono::DateTime<chrono::Utc>> {
-        println!("Try reading file '{:?}'", path);
+    fn read_exif(&self, path : &Path) -> Result<ExifDetails> {
+        debug!("Try reading file '{:?}'", path);
         let file = std::fs::File::open(path)?;
         let mut reader = std::io::BufReader::new(&file);
         let exifreader = exif::Reader::new();
         let exif = exifreader.read_from_container(&mut reader)?;
 
-        println!("Reading EXIF data ...");
+        debug!("Reading EXIF data ...");
 
         //for f in exif.fields() {
         //    println!("\t{} {} {}", f.tag, f.ifd_num, f.display_value())
         //}
 
-        match exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
+        let taken_on = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
             .or(exif.get_field(exif::Tag::DateTimeDigitized, exif::In::PRIMARY))
             .or(exif.get_field(exif::Tag::DateTime, exif::In::PRIMARY))
             .and_then(|dt| {
                 let date_str = dt.display_value().to_string();
-                println!("Found DateTime in exif data; '{}'", date_str);
+                debug!("Found DateTime in exif data; '{}'", date_str);
                 match chrono::Utc.datetime_from_str(date_str.as_str(), "%Y-%m-%d %H:%M:%S") {
                     Ok(dt) => Some(dt),
                     Err(e) => {
-                        println!("Encountered parse error => {}", e);
+                        warn!("Encountered parse error => {}", e);
                         None
                     }
                 }
-            }) {
-            Some(taken_on) => Ok(taken_on),
-            None => Err(FilesystemWatchdogError::WatchdogError(format!("No date filed given in EXIF data!")))
+            });
+        if taken_on.is_none() {
+            debug!("No date filed given in EXIF data!");
+        }
+
+        Ok(ExifDetails {
+            taken_on,
+            camera_make: Self::read_exif_text(&exif, exif::Tag::Make),
+            camera_model: Self::read_exif_text(&exif, exif::Tag::Model),
+        })
+    }
+
+    fn read_exif_text(exif: &exif::Exif, tag: exif::Tag) -> Option<String> {
+        match exif.get_field(tag, exif::In::PRIMARY).map(|field| &field.value) {
+            Some(exif::Value::Ascii(values)) => values.first()
+                .map(|value| String::from_utf8_lossy(value).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string())
+                .filter(|value| !value.is_empty()),
+            _ => None
         }
     }
 
@@ -14,71 +14,228 @@
  * limitations under the License.
  */
 
-use std::net::SocketAddr;
-use std::path::Path;
+use std::sync::Arc;
 
+use clap::Parser;
+use log::{error, info, LevelFilter};
+
+use crate::config::{Cli, Command, Config};
+use crate::file_system::inboxes::InboxSettings;
+
+mod api_error;
 mod api_handler;
+mod auth;
+mod config;
+mod tls;
 pub mod file_system;
 
 #[tokio::main]
 async fn main() {
-    let args : Vec<String> = std::env::args().collect();
-    let src_dir = Path::new(&args[1]);
-    println!("Using source directory: {:?}", src_dir);
-    let dst_conf = Path::new("destination_config.json");
-    let fs = file_system::FileSystem::new(src_dir, dst_conf);
-    let _jh = fs.launch_watchdog(src_dir).await;
-
-    let service = filters::endpoints(fs);
-
-    let env_ip_str = match std::env::var("SERVER_IP") {
-        Ok(given_ip) => given_ip,
-        Err(_) => "192.168.2.111:5555".to_string()
+    let cli = Cli::parse();
+    if let Some(Command::HashPassword) = cli.command {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password).expect("Failed to read the password from stdin!");
+        println!("{}", auth::hash_password(password.trim_end_matches(['\r', '\n'])));
+        return;
+    }
+
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // RUST_LOG still takes precedence, e.g. to debug a dependency
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Warn)
+        .filter_module("filebase", config.log_level)
+        .parse_default_env()
+        .init();
+
+    if let Err(e) = run(config).await {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(config: Config) -> Result<(), String> {
+    let inboxes = config.inboxes.iter()
+        .map(|inbox| {
+            info!("Using inbox '{}' at {:?}", inbox.name, inbox.path);
+            InboxSettings {
+                name: inbox.name.clone(),
+                path: inbox.path.clone(),
+                thumbnail_dir: inbox.thumbnail_dir(),
+                destinations: inbox.destinations.clone(),
+            }
+        })
+        .collect();
+    // checked first, so a missing user config doesn't leave the watchdogs running
+    let auth = auth::Auth::from_file(&config.users, config.disable_auth, config.tls.is_some())?;
+
+    let state_dir = config.state_dir();
+    info!("Using state directory: {:?}", state_dir);
+    let fs = file_system::FileSystem::new(state_dir.as_path(), inboxes, &config.destinations, &config.rules,
+                                          config.thumbnail_size, config.preview_max_size).await
+        .map_err(|e| format!("Failed to open the inboxes, destinations and rules: {:?}", e))?;
+    let _watchdogs = fs.launch_watchdogs().await;
+    let _purger = fs.launch_trash_purger(chrono::Duration::days(config.trash_retention_days)).await;
+    let _indexer = fs.launch_library_indexer(std::time::Duration::from_secs(config.library_index_interval_minutes * 60)).await;
+    let _job_runner = fs.launch_job_runner().await;
+    let _reloader = fs.launch_destination_reloader().await;
+    let _rule_runner = fs.launch_rule_runner().await;
+
+    let service = filters::endpoints(fs, auth, config.frontend.clone());
+
+    let certificate = match &config.tls {
+        Some(tls) => Some(Arc::new(tls::ReloadingCertificate::load(&tls.cert, &tls.key)
+            .map_err(|e| format!("Failed to load the TLS certificate: {}", e))?)),
+        None => None
     };
-    let sock_address: SocketAddr = env_ip_str.parse().unwrap();
+    // all listeners share the certificate, so one reloader serves them all
+    let _certificate_reloader = certificate.as_ref().map(|certificate| certificate.launch_reloader());
 
-    println!("Launching filebase-server. Listening on {}", sock_address);
+    let mut servers = Vec::with_capacity(config.listen.len());
+    for address in config.listen.iter() {
+        let server = match &certificate {
+            Some(certificate) => {
+                let listener = tokio::net::TcpListener::bind(address).await
+                    .map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+                info!("Launching filebase-server. Listening on https://{}", address);
+                tokio::spawn(tls::serve(service.clone(), listener, certificate.clone()))
+            }
+            None => {
+                let (address, server) = warp::serve(service.clone()).try_bind_ephemeral(*address)
+                    .map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+                info!("Launching filebase-server. Listening on http://{}", address);
+                tokio::spawn(server)
+            }
+        };
+        servers.push(server);
+    }
 
-    warp::serve(service).run(sock_address).await
+    for server in servers {
+        server.await.map_err(|e| format!("A server stopped: {}", e))?;
+    }
+    Ok(())
 }
 
 mod filters {
+    use std::path::PathBuf;
     use warp::Filter;
     use crate::api_handler;
+    use crate::auth;
+    use crate::auth::{Auth, Role};
     use crate::file_system;
     use crate::file_system::FileSystem;
 
     const CONTENT_LENGTH_LIMIT: u64 = 1024 * 32;
 
-    pub fn endpoints(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
-        api(fs).or(frontend())
+    pub fn endpoints(fs: file_system::FileSystem, auth: Auth, frontend_dir: PathBuf) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        api(fs, auth).or(frontend(frontend_dir))
     }
 
-    fn frontend() -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+    fn frontend(frontend_dir: PathBuf) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
         warp::any()
-            .and(warp::fs::dir("./frontend/public"))
+            .and(warp::fs::dir(frontend_dir))
     }
 
-    fn api(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+    /// Viewers may only use the routes which leave the inbox as it is; everything else requires an admin.
+    fn api(fs: file_system::FileSystem, auth: Auth) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
         warp::path("api")
             .and(warp::path("v1"))
             .and(
-                list_images(fs.clone())
-                    .or(load_image(fs.clone()))
-                    .or(confirm_images(fs.clone()))
-                    .or(discard_images(fs.clone()))
-                    .or(discard_all(fs.clone()))
-                    .or(list_destinations(fs))
+                login(auth.clone())
+                    .or(logout(auth.clone()))
+                    .or(whoami(auth.clone()))
+                    .or(require(auth.clone(), Role::Viewer).and(
+                        list_images(fs.clone())
+                            .or(group_images(fs.clone()))
+                            .or(load_image(fs.clone()))
+                            .or(load_original(fs.clone()))
+                            .or(load_preview(fs.clone()))
+                            .or(list_trash(fs.clone()))
+                            .or(list_journal(fs.clone()))
+                            .or(list_jobs(fs.clone()))
+                            .or(get_job(fs.clone()))
+                            .or(stream_events(fs.clone()))
+                            .or(list_destinations(fs.clone()))
+                            .or(list_inboxes(fs.clone()))
+                            .or(list_rules(fs.clone()))
+                            .or(simulate_rules(fs.clone()))
+                    ))
+                    .or(require(auth, Role::Admin).and(
+                        confirm_images(fs.clone())
+                            .or(discard_images(fs.clone()))
+                            .or(discard_all(fs.clone()))
+                            .or(discard_filed(fs.clone()))
+                            .or(restore_trashed(fs.clone()))
+                            .or(purge_trashed(fs.clone()))
+                            .or(purge_trash(fs.clone()))
+                            .or(undo_confirmed(fs.clone()))
+                            .or(submit_confirm_job(fs.clone()))
+                            .or(submit_discard_job(fs.clone()))
+                            .or(cancel_job(fs.clone()))
+                            .or(list_destination_settings(fs.clone()))
+                            .or(create_destination(fs.clone()))
+                            .or(update_destination(fs.clone()))
+                            .or(disable_destination(fs.clone()))
+                            .or(enable_destination(fs.clone()))
+                            .or(delete_destination(fs))
+                    ))
+                    .recover(api_handler::handle_rejection)
             )
     }
 
+    /// Lets only requests of users with at least `role` through.
+    fn require(auth: Auth, role: Role) -> impl warp::Filter<Extract=(), Error=warp::Rejection> + Clone {
+        auth::with_role(auth, role)
+            .map(|_| ())
+            .untuple_one()
+    }
+
+    fn login(auth: Auth) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("session")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(CONTENT_LENGTH_LIMIT))
+            .and(with_auth(auth))
+            .and(warp::body::json())
+            .and_then(api_handler::handle_login)
+    }
+
+    fn logout(auth: Auth) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("session")
+            .and(warp::delete())
+            .and(with_auth(auth))
+            .and(auth::token())
+            .and_then(api_handler::handle_logout)
+    }
+
+    fn whoami(auth: Auth) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("session")
+            .and(warp::get())
+            .and(auth::with_role(auth, Role::Viewer))
+            .and_then(api_handler::handle_whoami)
+    }
+
     fn list_images(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
         warp::path!("items")
             .and(warp::get())
             .and(with_fs(fs))
+            .and(warp::query::<api_handler::ListQuery>())
             .and_then(api_handler::handle_list_items)
     }
 
+    fn group_images(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("items" / "groups")
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and(warp::query::<api_handler::GroupQuery>())
+            .and_then(api_handler::handle_group_items)
+    }
+
     fn load_image(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
         warp::path!("items" / "load" / u64)
             .and(warp::get())
@@ -86,6 +243,21 @@ mod filters {
             .and_then(api_handler::handle_load_item)
     }
 
+    fn load_original(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("items" / "original" / u64)
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_load_original)
+    }
+
+    fn load_preview(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("items" / "preview" / u64)
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and(warp::query::<api_handler::PreviewQuery>())
+            .and_then(api_handler::handle_load_preview)
+    }
+
     fn confirm_images(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
         warp::path!("items" / "confirm")
             .and(warp::post())
@@ -108,17 +280,195 @@ mod filters {
         warp::path!("items" / "discard_all")
             .and(warp::post())
             .and(with_fs(fs))
+            .and(warp::query::<api_handler::InboxQuery>())
             .and_then(api_handler::handle_discard_all)
     }
 
+    fn discard_filed(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("items" / "discard_filed")
+            .and(warp::post())
+            .and(with_fs(fs))
+            .and(warp::query::<api_handler::InboxQuery>())
+            .and_then(api_handler::handle_discard_filed)
+    }
+
+    fn list_trash(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("trash")
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_list_trash)
+    }
+
+    fn restore_trashed(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("trash" / "restore")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(CONTENT_LENGTH_LIMIT))
+            .and(with_fs(fs))
+            .and(warp::body::json())
+            .and_then(api_handler::handle_restore_items)
+    }
+
+    fn purge_trashed(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("trash" / "purge")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(CONTENT_LENGTH_LIMIT))
+            .and(with_fs(fs))
+            .and(warp::body::json())
+            .and_then(api_handler::handle_purge_items)
+    }
+
+    fn purge_trash(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("trash" / "purge_all")
+            .and(warp::post())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_purge_all)
+    }
+
+    fn list_journal(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("journal")
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and(warp::query::<api_handler::JournalQuery>())
+            .and_then(api_handler::handle_list_journal)
+    }
+
+    fn undo_confirmed(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("journal" / "undo")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(CONTENT_LENGTH_LIMIT))
+            .and(with_fs(fs))
+            .and(warp::body::json())
+            .and_then(api_handler::handle_undo_items)
+    }
+
+    fn submit_confirm_job(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("jobs" / "confirm")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(CONTENT_LENGTH_LIMIT))
+            .and(with_fs(fs))
+            .and(warp::body::json())
+            .and_then(api_handler::handle_submit_confirm)
+    }
+
+    fn submit_discard_job(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("jobs" / "discard")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(CONTENT_LENGTH_LIMIT))
+            .and(with_fs(fs))
+            .and(warp::body::json())
+            .and_then(api_handler::handle_submit_discard)
+    }
+
+    fn list_jobs(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("jobs")
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and(warp::query::<api_handler::JobQuery>())
+            .and_then(api_handler::handle_list_jobs)
+    }
+
+    fn get_job(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("jobs" / u64)
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_get_job)
+    }
+
+    fn cancel_job(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("jobs" / u64 / "cancel")
+            .and(warp::post())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_cancel_job)
+    }
+
+    fn stream_events(fs: file_system::FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("events")
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_events)
+    }
+
     fn list_destinations(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
         warp::path!("destinations")
             .and(warp::get())
             .and(with_fs(fs))
+            .and(warp::query::<api_handler::InboxQuery>())
             .and_then(api_handler::handle_list_destinations)
     }
 
+    fn list_destination_settings(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("destinations" / "settings")
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_list_destination_settings)
+    }
+
+    fn create_destination(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("destinations")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(CONTENT_LENGTH_LIMIT))
+            .and(with_fs(fs))
+            .and(warp::body::json())
+            .and_then(api_handler::handle_create_destination)
+    }
+
+    fn update_destination(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("destinations" / String)
+            .and(warp::put())
+            .and(warp::body::content_length_limit(CONTENT_LENGTH_LIMIT))
+            .and(with_fs(fs))
+            .and(warp::body::json())
+            .and_then(api_handler::handle_update_destination)
+    }
+
+    fn disable_destination(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("destinations" / String / "disable")
+            .and(warp::post())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_disable_destination)
+    }
+
+    fn enable_destination(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("destinations" / String / "enable")
+            .and(warp::post())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_enable_destination)
+    }
+
+    fn delete_destination(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("destinations" / String)
+            .and(warp::delete())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_delete_destination)
+    }
+
+    fn list_rules(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("rules")
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_list_rules)
+    }
+
+    fn simulate_rules(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("rules" / "simulate")
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and(warp::query::<api_handler::InboxQuery>())
+            .and_then(api_handler::handle_simulate_rules)
+    }
+
+    fn list_inboxes(fs : FileSystem) -> impl warp::Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
+        warp::path!("inboxes")
+            .and(warp::get())
+            .and(with_fs(fs))
+            .and_then(api_handler::handle_list_inboxes)
+    }
+
     fn with_fs(fs: file_system::FileSystem) -> impl Filter<Extract=(file_system::FileSystem, ), Error=std::convert::Infallible> + Clone {
         warp::any().map(move || fs.clone())
     }
+
+    fn with_auth(auth: Auth) -> impl Filter<Extract=(Auth, ), Error=std::convert::Infallible> + Clone {
+        warp::any().map(move || auth.clone())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,214 @@
+/*
+ * Copyright 2021 nzelot<leontsteiner@gmail.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use hyper::server::conn::Http;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::TlsAcceptor;
+use log::{debug, info, warn};
+
+/// How often the certificate and key files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Failing accepts, e.g. for lack of file descriptors, are retried after a delay doubling up to the maximum
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const ACCEPT_MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum TlsError {
+    IOError(String),
+    InvalidCertificate(String),
+    InvalidKey(String),
+}
+
+/// Hands out the current certificate for every handshake, so a renewed certificate is used for
+/// new connections as soon as it is reloaded, without restarting the server.
+pub struct ReloadingCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>)>,
+}
+
+impl ReloadingCertificate {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, TlsError> {
+        let certified_key = load_certified_key(cert_path, key_path)?;
+        Ok(ReloadingCertificate {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new((Arc::new(certified_key), last_modified(cert_path, key_path))),
+        })
+    }
+
+    /// Periodically reloads the files once either of them was modified. Pairs which fail to load or
+    /// whose key doesn't match the certificate, e.g. because only one of both files was replaced yet,
+    /// keep the current pair in service and are retried next time.
+    pub fn launch_reloader(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let certificate = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                let modified = last_modified(&certificate.cert_path, &certificate.key_path);
+                if modified == certificate.current.read().expect("The certificate lock is poisoned!").1 {
+                    continue;
+                }
+
+                let (cert_path, key_path) = (certificate.cert_path.clone(), certificate.key_path.clone());
+                let loaded = tokio::task::spawn_blocking(move || load_certified_key(&cert_path, &key_path)).await;
+                match loaded {
+                    Ok(Ok(certified_key)) => {
+                        *certificate.current.write().expect("The certificate lock is poisoned!") = (Arc::new(certified_key), modified);
+                        info!("Reloaded the TLS certificate from {:?}", certificate.cert_path);
+                    }
+                    Ok(Err(e)) => warn!("Reloading the TLS certificate failed; Keeping the current one: {}", e),
+                    Err(e) => warn!("Reloading the TLS certificate failed; Keeping the current one: {:?}", e)
+                }
+            }
+        })
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().expect("The certificate lock is poisoned!").0.clone())
+    }
+}
+
+/// Serves `filter` over HTTPS to the connections of `listener`, speaking HTTP/1.1 and HTTP/2.
+/// The certificate is only reloaded once its reloader was launched.
+pub async fn serve<F>(filter: F, listener: TcpListener, certificate: Arc<ReloadingCertificate>)
+    where F: warp::Filter + Clone + Send + Sync + 'static,
+          F::Extract: warp::Reply {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(certificate);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let service = warp::service(filter);
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => {
+                backoff = ACCEPT_BACKOFF;
+                connection
+            }
+            Err(e) => {
+                warn!("Accepting a connection failed; Retrying in {:?}: {}", backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_MAX_BACKOFF);
+                continue;
+            }
+        };
+
+        let (acceptor, service) = (acceptor.clone(), service.clone());
+        tokio::spawn(async move {
+            match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    if let Err(e) = Http::new().serve_connection(stream, service).await {
+                        debug!("Serving {} failed: {}", peer, e);
+                    }
+                }
+                Err(e) => debug!("TLS handshake with {} failed: {}", peer, e)
+            }
+        });
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let mut reader = BufReader::new(File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<Certificate>>();
+    if certs.is_empty() {
+        return Err(TlsError::InvalidCertificate(format!("{:?} contains no PEM encoded certificate", cert_path)));
+    }
+
+    let mut reader = BufReader::new(File::open(key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(TlsError::InvalidKey(format!("{:?} contains no PEM encoded private key", key_path)))
+        }
+    };
+    let key = sign::any_supported_type(&key)
+        .map_err(|e| TlsError::InvalidKey(format!("{:?}: {}", key_path, e)))?;
+    check_key_matches(&certs[0], key.as_ref())
+        .map_err(|reason| TlsError::InvalidKey(format!("{:?} doesn't belong to the certificate in {:?}: {}", key_path, cert_path, reason)))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Signs a message with the key and verifies it with the certificate's public key.
+fn check_key_matches(cert: &Certificate, key: &dyn sign::SigningKey) -> Result<(), String> {
+    const SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 4] = [
+        (SignatureScheme::RSA_PSS_SHA256, &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY),
+        (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+        (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+    ];
+    let offered = SCHEMES.iter().map(|(scheme, _)| *scheme).collect::<Vec<SignatureScheme>>();
+    let signer = key.choose_scheme(&offered)
+        .ok_or_else(|| "the key supports none of the checked signature schemes".to_string())?;
+    let algorithm = SCHEMES.iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .map(|(_, algorithm)| *algorithm)
+        .ok_or_else(|| format!("unexpected signature scheme {:?}", signer.scheme()))?;
+
+    let message = b"filebase key check";
+    let signature = signer.sign(message).map_err(|e| e.to_string())?;
+    webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|e| format!("unreadable certificate: {:?}", e))?
+        .verify_signature(algorithm, message, &signature)
+        .map_err(|e| format!("{:?}", e))
+}
+
+fn last_modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let cert_modified = cert_path.metadata().and_then(|m| m.modified()).ok()?;
+    let key_modified = key_path.metadata().and_then(|m| m.modified()).ok()?;
+    Some(cert_modified.max(key_modified))
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::IOError(message) => write!(f, "Reading the certificate or key failed: {}", message),
+            TlsError::InvalidCertificate(message) => write!(f, "Invalid certificate: {}", message),
+            TlsError::InvalidKey(message) => write!(f, "Invalid private key: {}", message),
+        }
+    }
+}
+
+impl From<std::io::Error> for TlsError {
+    fn from(e: std::io::Error) -> Self {
+        TlsError::IOError(e.to_string())
+    }
+}